@@ -1,4 +1,5 @@
 import CoreAudio
+import Foundation
 
 func getDefaultAudioDevice() -> AudioDeviceID {
     var deviceId: AudioDeviceID = kAudioObjectUnknown
@@ -57,11 +58,37 @@ func getDeviceName(deviceId: AudioDeviceID) -> String? {
     return name as String
 }
 
-let deviceId = getDefaultAudioDevice()
-if deviceId != kAudioObjectUnknown {
-    if let name = getDeviceName(deviceId: deviceId) {
-        print(name)
-        exit(0)
+func currentDeviceName() -> String? {
+    let deviceId = getDefaultAudioDevice()
+    if deviceId == kAudioObjectUnknown { return nil }
+    return getDeviceName(deviceId: deviceId)
+}
+
+// --watch: print the current device, then again on every default output change.
+// An empty line means the device could not be determined.
+if CommandLine.arguments.contains("--watch") {
+    func report() {
+        print(currentDeviceName() ?? "")
+        fflush(stdout)
     }
+
+    var address = AudioObjectPropertyAddress(
+        mSelector: kAudioHardwarePropertyDefaultOutputDevice,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMain
+    )
+    AudioObjectAddPropertyListenerBlock(
+        AudioObjectID(kAudioObjectSystemObject),
+        &address,
+        DispatchQueue.main
+    ) { _, _ in report() }
+
+    report()
+    dispatchMain()
+}
+
+if let name = currentDeviceName() {
+    print(name)
+    exit(0)
 }
 exit(1)
@@ -0,0 +1,344 @@
+//! Command-line front end.
+//!
+//! `lgtv daemon` (or no subcommand) runs the controller. Every other subcommand is a thin
+//! client that forwards one request to the running daemon's control socket and prints the
+//! result, exiting with one of the `EXIT_*` codes below. With `--direct` the client skips the
+//! daemon and connects to the TV itself for that single command. `lgtv discover` lists the
+//! TVs on the network and can write one into the config file.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::command::TvCommand;
+use crate::config::{self, Config};
+use crate::connection::{connect_verified, execute};
+use crate::discovery;
+use crate::protocol::Response;
+use crate::volume::VolumeState;
+
+pub const EXIT_OK: i32 = 0;
+/// The daemon answered, but the request failed (TV not connected, command rejected...).
+pub const EXIT_FAILED: i32 = 1;
+/// Bad command line or configuration.
+pub const EXIT_USAGE: i32 = 2;
+/// The daemon is not running or did not answer.
+pub const EXIT_NO_DAEMON: i32 = 3;
+/// `--direct`: the TV could not be found or connected to in time.
+pub const EXIT_UNREACHABLE: i32 = 4;
+
+pub const DEFAULT_DIRECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the daemon may take to answer a request. Longer than a command timeout plus a
+/// replay after reconnecting, with the default `[connection]` settings.
+pub const DAEMON_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub const USAGE: &str = "\
+Usage: lgtv [--config PATH] [--mac MAC] [--audio-device NAME] [--pipe PATH] [--no-pipe]
+            [--socket PATH] [--direct [--timeout SECS]] [--json] [COMMAND]
+
+Commands:
+  daemon                     Run the controller (default when no command is given)
+  discover                   List the TVs on the network and pick one for the config file
+  status                     Print the TV volume and mute state
+  volume up|down [STEP]      Step the volume
+  volume set N | volume N    Set the volume (0-100)
+  mute | unmute              Mute or unmute
+  mute toggle                Flip the mute state
+  input NAME                 Switch input, e.g. hdmi2
+  launch APP                 Launch an app, e.g. netflix
+  channel up|down            Change channel
+  play|pause|stop|rewind|forward
+  toast MESSAGE...           Show a notification
+  power-off                  Turn the TV off
+  help                       Show this message
+
+--json prints the raw response line instead of a summary.
+--direct connects to the TV without a running daemon, prints the TV's payload and exits;
+--timeout (only with --direct) bounds the whole attempt (default 10s).
+Exit codes: 0 ok, 1 request failed, 2 usage error, 3 daemon not reachable,
+            4 TV not reachable (--direct).";
+
+pub enum Invocation {
+    Daemon,
+    Help,
+    /// List the TVs on the network.
+    Discover,
+    /// Send `command` through the running daemon, or straight to the TV when `direct` is set.
+    Client { command: TvCommand, output: Output, direct: Option<Duration> },
+}
+
+#[derive(Clone, Copy)]
+pub struct Output {
+    /// `status` subcommand: summarise the `GetVolume` payload.
+    pub status: bool,
+    pub json: bool,
+}
+
+fn parse_timeout(secs: &str) -> Result<Duration, String> {
+    secs.parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("invalid --timeout '{}'", secs))
+}
+
+/// Interprets the arguments left after the config flags.
+pub fn parse(args: &[String]) -> Result<Invocation, String> {
+    let mut json = false;
+    let mut direct = false;
+    let mut timeout = None;
+    let mut positional: Vec<&str> = Vec::new();
+
+    let mut iter = args.iter().map(String::as_str);
+    while let Some(arg) = iter.next() {
+        match arg.split_once('=') {
+            Some(("--timeout", secs)) => timeout = Some(parse_timeout(secs)?),
+            _ => match arg {
+                "--json" => json = true,
+                "--direct" => direct = true,
+                "--timeout" => timeout = Some(parse_timeout(iter.next().ok_or("missing value for --timeout")?)?),
+                flag if flag.starts_with("--") && flag != "--help" => {
+                    return Err(format!("unknown argument '{}'", flag));
+                }
+                _ => positional.push(arg),
+            },
+        }
+    }
+    let args = positional;
+    if timeout.is_some() && !direct {
+        return Err("--timeout only applies with --direct".to_string());
+    }
+    let direct = direct.then(|| timeout.unwrap_or(DEFAULT_DIRECT_TIMEOUT));
+
+    let client = |command: TvCommand| {
+        Ok(Invocation::Client { command, output: Output { status: false, json }, direct })
+    };
+    let parts = |name: &str, rest: &[&str]| {
+        let rest: Vec<String> = rest.iter().map(|s| s.to_string()).collect();
+        TvCommand::from_parts(name, &rest).map_err(|e| e.to_string())
+    };
+
+    match args.as_slice() {
+        [] | ["daemon"] if direct.is_none() => Ok(Invocation::Daemon),
+        [] | ["daemon"] => Err("--direct needs a command".to_string()),
+        ["help"] | ["-h"] | ["--help"] => Ok(Invocation::Help),
+        ["discover"] if direct.is_none() => Ok(Invocation::Discover),
+        ["discover"] => Err("--direct does not apply to discover".to_string()),
+        ["status"] => Ok(Invocation::Client {
+            command: TvCommand::GetVolume,
+            output: Output { status: true, json },
+            direct,
+        }),
+        ["volume", "up", step @ ..] => client(parts("volume_up", step)?),
+        ["volume", "down", step @ ..] => client(parts("volume_down", step)?),
+        ["volume", "set", level] | ["volume", level] => client(parts("volume", &[level])?),
+        ["mute", "toggle"] | ["mute-toggle"] => client(TvCommand::MuteToggle),
+        ["channel", "up"] => client(TvCommand::ChannelUp),
+        ["channel", "down"] => client(TvCommand::ChannelDown),
+        ["power-off"] | ["off"] => client(TvCommand::PowerOff),
+        [name @ ("mute" | "unmute" | "play" | "pause" | "stop" | "rewind" | "forward"), rest @ ..]
+        | [name @ ("input" | "launch" | "toast"), rest @ ..] => client(parts(name, rest)?),
+        [other, ..] => Err(format!("unknown command '{}'", other)),
+    }
+}
+
+/// Sends one request to the daemon and prints the result. Returns the process exit code.
+pub async fn run(socket_path: &Path, command: &TvCommand, output: Output) -> i32 {
+    let response = match tokio::time::timeout(DAEMON_TIMEOUT, request(socket_path, &command.to_string())).await {
+        Ok(Ok(line)) => line,
+        Ok(Err(e)) => {
+            eprintln!("lgtv: cannot reach the daemon at {}: {}", socket_path.display(), e);
+            eprintln!("lgtv: is `lgtv daemon` running?");
+            return EXIT_NO_DAEMON;
+        }
+        Err(_) => {
+            eprintln!("lgtv: the daemon at {} did not answer within {:?}", socket_path.display(), DAEMON_TIMEOUT);
+            return EXIT_NO_DAEMON;
+        }
+    };
+
+    match serde_json::from_str::<Value>(&response) {
+        Ok(value) => report(&value, output, false),
+        Err(e) => {
+            eprintln!("lgtv: unreadable response from daemon: {}", e);
+            EXIT_NO_DAEMON
+        }
+    }
+}
+
+/// Connects to the TV without the daemon, sends one command and prints the payload.
+pub async fn run_direct(config: &Config, command: &TvCommand, output: Output, timeout: Duration) -> i32 {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mac = config.normalized_mac();
+    let connecting = connect_verified(&mac, &config.connection, &config.discovery, &config.identity, |_, _| {});
+    let client = match tokio::time::timeout_at(deadline, connecting).await {
+        Ok(Ok((c, _))) => c,
+        Ok(Err(e)) => {
+            eprintln!("lgtv: {}", e);
+            return EXIT_UNREACHABLE;
+        }
+        Err(_) => {
+            eprintln!("lgtv: TV unreachable: no connection within {:?}", timeout);
+            return EXIT_UNREACHABLE;
+        }
+    };
+
+    let response = match tokio::time::timeout_at(deadline, execute(&client, command, &config.volume)).await {
+        Ok(Ok(payload)) => Response::success(None, payload),
+        Ok(Err(e)) => Response::failure(None, e),
+        Err(_) => Response::failure(None, format!("TV did not answer within {:?}", timeout)),
+    };
+    let value = serde_json::to_value(&response).expect("Response is always serialisable");
+    report(&value, output, true)
+}
+
+/// Finds the TVs on the network, prints them as a table and, when run in a terminal, offers
+/// to write the chosen one's MAC address into the config file at `config_path`.
+pub async fn run_discover(config: &Config, config_path: &Path) -> i32 {
+    let configured = (!config.mac.is_empty()).then(|| config.normalized_mac());
+    println!("Searching for TVs...");
+    let (tvs, warnings) = discovery::discover(configured.as_deref(), &config.discovery).await;
+    for warning in &warnings {
+        eprintln!("lgtv: {}", warning);
+    }
+    if tvs.is_empty() {
+        eprintln!("lgtv: no TV found; check that it is on and on the same network as this machine");
+        return EXIT_FAILED;
+    }
+
+    let unknown = || "?".to_string();
+    let mut rows = vec![["#", "IP", "MAC", "MODEL", "NAME", "FOUND BY"].map(String::from)];
+    for (i, tv) in tvs.iter().enumerate() {
+        let mut mac = tv.mac.clone().unwrap_or_else(unknown);
+        if tv.mac.is_some() && tv.mac == configured {
+            mac.push_str(" (configured)");
+        }
+        rows.push([
+            (i + 1).to_string(),
+            tv.ip.clone(),
+            mac,
+            tv.model.clone().unwrap_or_else(unknown),
+            tv.name.clone().unwrap_or_else(unknown),
+            tv.found_by.join(", "),
+        ]);
+    }
+    let widths: Vec<usize> = (0..6).map(|col| rows.iter().map(|r| r[col].chars().count()).max().unwrap_or(0)).collect();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, w)| format!("{:<w$}", cell, w = w)).collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return EXIT_OK;
+    }
+    print!("Write which TV's MAC address to {}? [1-{}, Enter to skip] ", config_path.display(), tvs.len());
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() || answer.trim().is_empty() {
+        return EXIT_OK;
+    }
+    let Some(tv) = answer.trim().parse::<usize>().ok().and_then(|n| tvs.get(n.wrapping_sub(1))) else {
+        eprintln!("lgtv: '{}' is not one of the listed TVs", answer.trim());
+        return EXIT_USAGE;
+    };
+    let Some(mac) = &tv.mac else {
+        eprintln!("lgtv: the MAC address of {} is unknown; try again once it is on", tv.ip);
+        return EXIT_FAILED;
+    };
+    match config::write_mac(config_path, mac) {
+        Ok(()) => {
+            println!("Wrote mac = \"{}\" to {}", mac, config_path.display());
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("lgtv: cannot write {}: {}", config_path.display(), e);
+            EXIT_FAILED
+        }
+    }
+}
+
+/// Prints a response and maps it to an exit code.
+fn report(response: &Value, output: Output, print_payload: bool) -> i32 {
+    let ok = response.get("ok").and_then(Value::as_bool).unwrap_or(false);
+
+    if output.json {
+        println!("{}", response);
+    } else if !ok {
+        let error = response.get("error").and_then(Value::as_str).unwrap_or("unknown error");
+        eprintln!("lgtv: {}", error);
+    } else if output.status {
+        print_status(response.get("payload"));
+    } else if print_payload && let Some(payload) = response.get("payload") {
+        println!("{}", serde_json::to_string_pretty(payload).unwrap_or_default());
+    }
+
+    if ok { EXIT_OK } else { EXIT_FAILED }
+}
+
+async fn request(socket_path: &Path, line: &str) -> std::io::Result<String> {
+    let stream = UnixStream::connect(socket_path).await?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{}\n", line).as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(read).read_line(&mut response).await?;
+    if response.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    Ok(response)
+}
+
+fn print_status(payload: Option<&Value>) {
+    let state = payload.map(VolumeState::from_payload).unwrap_or_default();
+
+    match state.volume {
+        Some(v) => println!("Volume: {}", v),
+        None => println!("Volume: unknown"),
+    }
+    match state.muted {
+        Some(m) => println!("Muted:  {}", if m { "yes" } else { "no" }),
+        None => println!("Muted:  unknown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Invocation, String> {
+        parse(&line.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    fn direct(line: &str) -> Option<Duration> {
+        match parse_line(line) {
+            Ok(Invocation::Client { direct, .. }) => direct,
+            Ok(_) => panic!("'{}' is not a client command", line),
+            Err(e) => panic!("'{}': {}", line, e),
+        }
+    }
+
+    #[test]
+    fn parses_direct_timeout() {
+        assert_eq!(direct("mute"), None);
+        assert_eq!(direct("--direct mute"), Some(DEFAULT_DIRECT_TIMEOUT));
+        assert_eq!(direct("--direct --timeout 3 mute"), Some(Duration::from_secs(3)));
+        assert_eq!(direct("mute --timeout=2.5 --direct"), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn rejects_bad_timeouts() {
+        let error = |line: &str| parse_line(line).err().unwrap_or_else(|| panic!("'{}' parsed", line));
+        assert_eq!(error("--timeout 3 mute"), "--timeout only applies with --direct");
+        assert_eq!(error("--timeout=3 status"), "--timeout only applies with --direct");
+        assert_eq!(error("--direct mute --timeout"), "missing value for --timeout");
+        assert_eq!(error("--direct --timeout=0 mute"), "invalid --timeout '0'");
+        assert_eq!(error("--direct --timeout= mute"), "invalid --timeout ''");
+        assert_eq!(error("--direct --timeout=inf mute"), "invalid --timeout 'inf'");
+        assert_eq!(error("--json=yes mute"), "unknown argument '--json=yes'");
+    }
+}
@@ -0,0 +1,429 @@
+//! Command grammar for lines written to the pipe.
+//!
+//! A line is `<name> [arguments...]`. Arguments are separated by whitespace and may be
+//! quoted with `"` or `'` to include spaces; inside double quotes `\"` and `\\` are escapes.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TvCommand {
+    /// Volume step, optionally overriding the configured step size.
+    VolumeUp(Option<u8>),
+    VolumeDown(Option<u8>),
+    /// Absolute volume, 0-100.
+    SetVolume(u8),
+    /// Reads the current volume and mute state.
+    GetVolume,
+    Mute,
+    Unmute,
+    /// Flips the current mute state.
+    MuteToggle,
+    PowerOff,
+    /// webOS input ID, e.g. `HDMI_2`.
+    Input(String),
+    /// webOS app ID, e.g. `youtube.leanback.v4`.
+    Launch(String),
+    ChannelUp,
+    ChannelDown,
+    Play,
+    Pause,
+    Stop,
+    Rewind,
+    Forward,
+    Toast(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnterminatedQuote,
+    UnknownCommand(String),
+    MissingArgument { command: &'static str, expected: &'static str },
+    UnexpectedArgument { command: &'static str, argument: String },
+    InvalidNumber { command: &'static str, value: String },
+    OutOfRange { command: &'static str, value: i64, min: i64, max: i64 },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::UnterminatedQuote => write!(f, "unterminated quote"),
+            ParseError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            ParseError::MissingArgument { command, expected } => {
+                write!(f, "'{}' needs an argument: {}", command, expected)
+            }
+            ParseError::UnexpectedArgument { command, argument } => {
+                write!(f, "'{}' takes no arguments, got '{}'", command, argument)
+            }
+            ParseError::InvalidNumber { command, value } => {
+                write!(f, "'{}' expects a number, got '{}'", command, value)
+            }
+            ParseError::OutOfRange { command, value, min, max } => {
+                write!(f, "'{}' value {} is out of range {}-{}", command, value, min, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl TvCommand {
+    /// Volume/mute commands, which are only forwarded from the pipe while the TV is the active
+    /// audio output.
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self,
+            TvCommand::VolumeUp(_)
+                | TvCommand::VolumeDown(_)
+                | TvCommand::SetVolume(_)
+                | TvCommand::Mute
+                | TvCommand::Unmute
+                | TvCommand::MuteToggle
+        )
+    }
+
+    /// Direction (+1 / -1) and explicit step size of volume step commands.
+    pub fn volume_step(&self) -> Option<(i64, Option<u8>)> {
+        match self {
+            TvCommand::VolumeUp(step) => Some((1, *step)),
+            TvCommand::VolumeDown(step) => Some((-1, *step)),
+            _ => None,
+        }
+    }
+
+    /// Whether sending the command twice has the same effect as sending it once. Only these
+    /// are replayed after a dropped connection, which may have delivered the first attempt.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            TvCommand::SetVolume(_)
+            | TvCommand::GetVolume
+            | TvCommand::Mute
+            | TvCommand::Unmute
+            | TvCommand::Input(_)
+            | TvCommand::Launch(_)
+            | TvCommand::Play
+            | TvCommand::Pause
+            | TvCommand::Stop => true,
+            TvCommand::VolumeUp(_)
+            | TvCommand::VolumeDown(_)
+            | TvCommand::MuteToggle
+            | TvCommand::PowerOff
+            | TvCommand::ChannelUp
+            | TvCommand::ChannelDown
+            | TvCommand::Rewind
+            | TvCommand::Forward
+            | TvCommand::Toast(_) => false,
+        }
+    }
+
+    /// Builds a command from its name and already-split arguments.
+    pub fn from_parts(name: &str, args: &[String]) -> Result<Self, ParseError> {
+        let command = match name.to_lowercase().as_str() {
+            "set_volume" => "volume",
+            lower => NAMES
+                .iter()
+                .copied()
+                .find(|n| *n == lower)
+                .ok_or_else(|| ParseError::UnknownCommand(name.to_string()))?,
+        };
+        let args = Args { command, items: args };
+
+        let command = match command {
+            "volume_up" => TvCommand::VolumeUp(args.optional_number(1, 100)?.map(|n| n as u8)),
+            "volume_down" => TvCommand::VolumeDown(args.optional_number(1, 100)?.map(|n| n as u8)),
+            "volume" => {
+                let v = args.single("a level between 0 and 100")?;
+                TvCommand::SetVolume(args.number(v, 0, 100)? as u8)
+            }
+            "get_volume" => args.none(TvCommand::GetVolume)?,
+            "mute" => args.none(TvCommand::Mute)?,
+            "unmute" => args.none(TvCommand::Unmute)?,
+            "mute_toggle" => args.none(TvCommand::MuteToggle)?,
+            "power_off" => args.none(TvCommand::PowerOff)?,
+            "input" => TvCommand::Input(input_id(args.single("an input such as HDMI_2")?)),
+            "launch" => TvCommand::Launch(app_id(args.single("an app name or ID")?).to_string()),
+            "channel_up" => args.none(TvCommand::ChannelUp)?,
+            "channel_down" => args.none(TvCommand::ChannelDown)?,
+            "play" => args.none(TvCommand::Play)?,
+            "pause" => args.none(TvCommand::Pause)?,
+            "stop" => args.none(TvCommand::Stop)?,
+            "rewind" => args.none(TvCommand::Rewind)?,
+            "forward" => args.none(TvCommand::Forward)?,
+            // Unquoted words are joined, so `toast Dinner is ready` works as expected.
+            "toast" => TvCommand::Toast(args.rest("a message")?),
+            _ => unreachable!("'{}' is in NAMES but has no parser", command),
+        };
+        Ok(command)
+    }
+}
+
+/// Canonical command names; `set_volume` is accepted as an alias of `volume`.
+const NAMES: &[&str] = &[
+    "volume_up", "volume_down", "volume", "get_volume", "mute", "unmute", "mute_toggle", "power_off", "input",
+    "launch", "channel_up", "channel_down", "play", "pause", "stop", "rewind", "forward", "toast",
+];
+
+impl FromStr for TvCommand {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(line)?;
+        let (name, args) = tokens.split_first().ok_or(ParseError::Empty)?;
+        TvCommand::from_parts(name, args)
+    }
+}
+
+impl fmt::Display for TvCommand {
+    /// Canonical line form; parsing it yields the same command.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TvCommand::VolumeUp(None) => write!(f, "volume_up"),
+            TvCommand::VolumeUp(Some(step)) => write!(f, "volume_up {}", step),
+            TvCommand::VolumeDown(None) => write!(f, "volume_down"),
+            TvCommand::VolumeDown(Some(step)) => write!(f, "volume_down {}", step),
+            TvCommand::SetVolume(v) => write!(f, "volume {}", v),
+            TvCommand::GetVolume => write!(f, "get_volume"),
+            TvCommand::Mute => write!(f, "mute"),
+            TvCommand::Unmute => write!(f, "unmute"),
+            TvCommand::MuteToggle => write!(f, "mute_toggle"),
+            TvCommand::PowerOff => write!(f, "power_off"),
+            TvCommand::Input(id) => write!(f, "input {}", quote(id)),
+            TvCommand::Launch(id) => write!(f, "launch {}", quote(id)),
+            TvCommand::ChannelUp => write!(f, "channel_up"),
+            TvCommand::ChannelDown => write!(f, "channel_down"),
+            TvCommand::Play => write!(f, "play"),
+            TvCommand::Pause => write!(f, "pause"),
+            TvCommand::Stop => write!(f, "stop"),
+            TvCommand::Rewind => write!(f, "rewind"),
+            TvCommand::Forward => write!(f, "forward"),
+            TvCommand::Toast(msg) => write!(f, "toast {}", quote(msg)),
+        }
+    }
+}
+
+/// Argument list of a single command, carrying the command name for error messages.
+struct Args<'a> {
+    command: &'static str,
+    items: &'a [String],
+}
+
+impl<'a> Args<'a> {
+    fn none(&self, command: TvCommand) -> Result<TvCommand, ParseError> {
+        match self.items.first() {
+            None => Ok(command),
+            Some(arg) => Err(ParseError::UnexpectedArgument { command: self.command, argument: arg.clone() }),
+        }
+    }
+
+    fn single(&self, expected: &'static str) -> Result<&'a str, ParseError> {
+        match self.items {
+            [] => Err(ParseError::MissingArgument { command: self.command, expected }),
+            [arg] => Ok(arg),
+            [_, extra, ..] => Err(ParseError::UnexpectedArgument { command: self.command, argument: extra.clone() }),
+        }
+    }
+
+    fn optional_number(&self, min: i64, max: i64) -> Result<Option<i64>, ParseError> {
+        match self.items {
+            [] => Ok(None),
+            [arg] => self.number(arg, min, max).map(Some),
+            [_, extra, ..] => Err(ParseError::UnexpectedArgument { command: self.command, argument: extra.clone() }),
+        }
+    }
+
+    fn rest(&self, expected: &'static str) -> Result<String, ParseError> {
+        if self.items.is_empty() {
+            return Err(ParseError::MissingArgument { command: self.command, expected });
+        }
+        Ok(self.items.join(" "))
+    }
+
+    fn number(&self, value: &str, min: i64, max: i64) -> Result<i64, ParseError> {
+        let n = value
+            .parse::<i64>()
+            .map_err(|_| ParseError::InvalidNumber { command: self.command, value: value.to_string() })?;
+        if n < min || n > max {
+            return Err(ParseError::OutOfRange { command: self.command, value: n, min, max });
+        }
+        Ok(n)
+    }
+}
+
+fn tokenize(line: &str) -> Result<Vec<String>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut token = String::new();
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() => break,
+                '"' | '\'' => {
+                    chars.next();
+                    let mut closed = false;
+                    while let Some(q) = chars.next() {
+                        if q == c {
+                            closed = true;
+                            break;
+                        }
+                        if c == '"' && q == '\\' {
+                            match chars.next() {
+                                Some(escaped) => token.push(escaped),
+                                None => break,
+                            }
+                        } else {
+                            token.push(q);
+                        }
+                    }
+                    if !closed {
+                        return Err(ParseError::UnterminatedQuote);
+                    }
+                }
+                _ => {
+                    token.push(c);
+                    chars.next();
+                }
+            }
+        }
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Maps friendly app names to webOS app IDs; anything else is passed through as an ID.
+fn app_id(name: &str) -> &str {
+    match name.to_lowercase().as_str() {
+        "netflix" => "netflix",
+        "youtube" => "youtube.leanback.v4",
+        "prime" | "amazon" => "amazon",
+        "disney" | "disneyplus" => "com.disney.disneyplus-prod",
+        "browser" => "com.webos.app.browser",
+        "livetv" | "tv" => "com.webos.app.livetv",
+        _ => name,
+    }
+}
+
+/// Normalises input names so `hdmi2`, `HDMI2` and `HDMI_2` all mean `HDMI_2`.
+fn input_id(name: &str) -> String {
+    let upper = name.to_uppercase();
+    match upper.strip_prefix("HDMI") {
+        Some(n) if !n.is_empty() && !n.starts_with('_') => format!("HDMI_{}", n),
+        _ => upper,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<TvCommand, ParseError> {
+        line.parse()
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_escapes() {
+        assert_eq!(tokenize("  input   HDMI_2 ").unwrap(), ["input", "HDMI_2"]);
+        assert_eq!(tokenize(r#"toast "Dinner is ready""#).unwrap(), ["toast", "Dinner is ready"]);
+        assert_eq!(tokenize("toast 'single \"quoted\"'").unwrap(), ["toast", "single \"quoted\""]);
+        assert_eq!(tokenize(r#"toast "a \"b\" \\ c""#).unwrap(), ["toast", r#"a "b" \ c"#]);
+        // Backslashes are literal outside double quotes.
+        assert_eq!(tokenize(r"toast 'a\b' c\d").unwrap(), ["toast", r"a\b", r"c\d"]);
+        assert_eq!(tokenize(r#"toast pre"fix suf"fix"#).unwrap(), ["toast", "prefix suffix"]);
+        assert_eq!(tokenize(r#"toast """#).unwrap(), ["toast", ""]);
+        assert_eq!(tokenize("   ").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse(r#"toast "never closed"#), Err(ParseError::UnterminatedQuote));
+        assert_eq!(parse("toast 'never closed"), Err(ParseError::UnterminatedQuote));
+        assert_eq!(parse(r#"toast "ends in \"#), Err(ParseError::UnterminatedQuote));
+        assert_eq!(
+            parse("volume 101"),
+            Err(ParseError::OutOfRange { command: "volume", value: 101, min: 0, max: 100 })
+        );
+        assert_eq!(
+            parse("volume_up 0"),
+            Err(ParseError::OutOfRange { command: "volume_up", value: 0, min: 1, max: 100 })
+        );
+        assert_eq!(
+            parse("mute now"),
+            Err(ParseError::UnexpectedArgument { command: "mute", argument: "now".into() })
+        );
+        assert_eq!(
+            parse("volume 10 20"),
+            Err(ParseError::UnexpectedArgument { command: "volume", argument: "20".into() })
+        );
+        assert_eq!(parse("volume loud"), Err(ParseError::InvalidNumber { command: "volume", value: "loud".into() }));
+        assert_eq!(parse("input"), Err(ParseError::MissingArgument { command: "input", expected: "an input such as HDMI_2" }));
+        assert_eq!(parse("volum 10"), Err(ParseError::UnknownCommand("volum".into())));
+    }
+
+    #[test]
+    fn every_name_has_a_parser() {
+        for name in NAMES {
+            let _ = TvCommand::from_parts(name, &[]);
+        }
+        assert_eq!(parse("SET_VOLUME 7"), Ok(TvCommand::SetVolume(7)));
+        assert_eq!(parse("set_volume"), Err(ParseError::MissingArgument { command: "volume", expected: "a level between 0 and 100" }));
+    }
+
+    #[test]
+    fn normalises_inputs_and_apps() {
+        assert_eq!(parse("input hdmi2"), Ok(TvCommand::Input("HDMI_2".into())));
+        assert_eq!(parse("input HDMI_3"), Ok(TvCommand::Input("HDMI_3".into())));
+        assert_eq!(parse("input av_1"), Ok(TvCommand::Input("AV_1".into())));
+        assert_eq!(parse("launch youtube"), Ok(TvCommand::Launch("youtube.leanback.v4".into())));
+        assert_eq!(parse("launch YouTube"), Ok(TvCommand::Launch("youtube.leanback.v4".into())));
+        assert_eq!(parse("launch com.example.app"), Ok(TvCommand::Launch("com.example.app".into())));
+        assert_eq!(parse("VOLUME_UP 5"), Ok(TvCommand::VolumeUp(Some(5))));
+        assert_eq!(parse("toast Dinner is ready"), Ok(TvCommand::Toast("Dinner is ready".into())));
+    }
+
+    #[test]
+    fn relative_commands_are_not_idempotent() {
+        for line in ["volume 20", "get_volume", "mute", "unmute", "input hdmi1", "launch netflix", "play", "pause", "stop"] {
+            assert!(parse(line).unwrap().is_idempotent(), "{}", line);
+        }
+        for line in ["volume_up", "volume_down 3", "mute_toggle", "power_off", "channel_up", "channel_down", "rewind", "forward", "toast hi"] {
+            assert!(!parse(line).unwrap().is_idempotent(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let commands = [
+            TvCommand::VolumeUp(None),
+            TvCommand::VolumeUp(Some(3)),
+            TvCommand::VolumeDown(Some(100)),
+            TvCommand::SetVolume(0),
+            TvCommand::GetVolume,
+            TvCommand::MuteToggle,
+            TvCommand::PowerOff,
+            TvCommand::Input("HDMI_2".into()),
+            TvCommand::Launch("com.disney.disneyplus-prod".into()),
+            TvCommand::ChannelDown,
+            TvCommand::Forward,
+            TvCommand::Toast("Dinner is ready".into()),
+            TvCommand::Toast(r#"say "hi" \ 'bye'"#.into()),
+            TvCommand::Toast("".into()),
+        ];
+        for command in commands {
+            assert_eq!(parse(&command.to_string()), Ok(command.clone()), "{}", command);
+        }
+    }
+}
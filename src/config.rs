@@ -0,0 +1,739 @@
+//! Runtime configuration.
+//!
+//! Values are layered, later sources winning:
+//! 1. Built-in defaults.
+//! 2. Config file (`~/.config/lgtv/config.toml`, or `$XDG_CONFIG_HOME/lgtv/config.toml`).
+//! 3. Environment variables (`LGTV_MAC`, `LGTV_AUDIO_DEVICE`, `LGTV_PIPE_PATH`, `LGTV_SOCKET_PATH`).
+//! 4. CLI flags (`--mac`, `--audio-device`, `--pipe`, `--no-pipe`, `--socket`).
+//!
+//! `--config <path>` / `LGTV_CONFIG` point at a different config file.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use home::home_dir;
+use nix::unistd::{AccessFlags, access};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PIPE_PATH: &str = "/tmp/lgtv-pipe";
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/lgtv.sock";
+
+// The name reported by CoreAudio for the LG TV when used as an audio sink.
+pub const DEFAULT_AUDIO_DEVICE: &str = "LG Monitor";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The MAC address of your LG TV.
+    pub mac: String,
+    /// Audio output name that means "the TV is playing sound".
+    pub audio_device: String,
+    /// How to find out which audio output is active.
+    pub audio: AudioConfig,
+    /// Volume key handling.
+    pub volume: VolumeConfig,
+    /// Reconnection and health checks.
+    pub connection: ConnectionConfig,
+    /// How the TV's IP address is found.
+    pub discovery: DiscoveryConfig,
+    /// What the TV must report about itself before commands are sent to it.
+    pub identity: IdentityConfig,
+    /// Named pipe Karabiner writes commands into.
+    pub pipe_path: PathBuf,
+    /// Whether to listen on the legacy named pipe at all.
+    pub pipe_enabled: bool,
+    /// Unix socket that answers every request with a response line.
+    pub socket_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mac: String::new(),
+            audio_device: DEFAULT_AUDIO_DEVICE.to_string(),
+            audio: AudioConfig::default(),
+            volume: VolumeConfig::default(),
+            connection: ConnectionConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            identity: IdentityConfig::default(),
+            pipe_path: PathBuf::from(DEFAULT_PIPE_PATH),
+            pipe_enabled: true,
+            socket_path: PathBuf::from(DEFAULT_SOCKET_PATH),
+        }
+    }
+}
+
+const CONFIG_FLAGS: &[&str] = &["--config", "--mac", "--audio-device", "--pipe", "--no-pipe", "--socket"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackend {
+    /// macOS `get_audio_device` helper.
+    Swift,
+    /// `pactl` (PulseAudio, or PipeWire with pipewire-pulse).
+    Pulseaudio,
+    /// `wpctl` (native PipeWire).
+    Pipewire,
+    /// `/proc/asound`: the card that is currently playing.
+    Alsa,
+    /// `audio.command`, compared by its stdout.
+    Command,
+    /// No gating: the TV is always treated as active.
+    Always,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub backend: AudioBackend,
+    /// Program and arguments for the `command` backend.
+    pub command: Vec<String>,
+    /// How long a probe result is reused, in milliseconds. 0 probes on every event.
+    pub cache_ttl_ms: u64,
+    /// Optional long-running helper that prints the output name whenever it changes,
+    /// e.g. `["/Users/me/.local/bin/get_audio_device", "--watch"]`.
+    pub watch_command: Vec<String>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            backend: if cfg!(target_os = "macos") { AudioBackend::Swift } else { AudioBackend::Pulseaudio },
+            command: Vec::new(),
+            cache_ttl_ms: 500,
+            watch_command: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// After a volume key event, further volume events arriving within this many
+    /// milliseconds are merged into the same `SetVolume`. 0 disables coalescing.
+    pub coalesce_ms: u64,
+    /// Volume change per key press, unless the command names its own (`volume_up 5`).
+    pub step: u8,
+    /// Presses in the same direction less than this many milliseconds apart grow the step
+    /// by one each, up to `accel_max_step`. 0 disables acceleration.
+    pub accel_window_ms: u64,
+    pub accel_max_step: u8,
+    /// Volume is never set above this, whatever the command.
+    pub max_volume: u8,
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        VolumeConfig { coalesce_ms: 40, step: 1, accel_window_ms: 0, accel_max_step: 5, max_volume: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// Delay before the first reconnection attempt, in milliseconds. Doubles after every
+    /// failed attempt, up to `retry_max_ms`, with random jitter on top.
+    pub retry_initial_ms: u64,
+    pub retry_max_ms: u64,
+    /// How often a connected TV is pinged, in milliseconds. 0 disables health checks.
+    pub health_interval_ms: u64,
+    /// How long opening the WebSocket may take, in milliseconds.
+    pub connect_timeout_ms: u64,
+    /// How long registration may take, in milliseconds. The first pairing waits for the
+    /// prompt on the TV to be accepted, so this is generous.
+    pub pair_timeout_ms: u64,
+    /// How long a command, including health checks, may wait for the TV's answer.
+    pub command_timeout_ms: u64,
+    /// A command that failed because the connection dropped is sent again after reconnecting,
+    /// if that happens within this many milliseconds. 0 disables replay.
+    pub replay_deadline_ms: u64,
+    /// How many failed commands are kept for replay; the oldest is dropped first.
+    pub replay_queue: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            retry_initial_ms: 500,
+            retry_max_ms: 30_000,
+            health_interval_ms: 30_000,
+            connect_timeout_ms: 5_000,
+            pair_timeout_ms: 60_000,
+            command_timeout_ms: 5_000,
+            replay_deadline_ms: 5_000,
+            replay_queue: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Search for the TV with SSDP when its MAC address isn't in the neighbour table.
+    pub ssdp: bool,
+    /// How long to wait for TVs to answer the search, in milliseconds.
+    pub ssdp_timeout_ms: u64,
+    /// As a last resort, probe every address on the local subnet so the TV's MAC address
+    /// shows up in the neighbour table.
+    pub sweep: bool,
+    /// Probes sent per second during a sweep.
+    pub sweep_rate: u32,
+    /// How long a sweep may take, in milliseconds, including waiting for replies.
+    pub sweep_timeout_ms: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig { ssdp: true, ssdp_timeout_ms: 2_000, sweep: false, sweep_rate: 100, sweep_timeout_ms: 5_000 }
+    }
+}
+
+/// Each field that is set must match what the TV reports, or the connection is dropped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Model name, e.g. `OLED55C9PLA`.
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// UPnP UUID, as `lgtv discover` finds it over SSDP.
+    pub uuid: Option<String>,
+}
+
+/// Values that override the config file, collected from the environment or CLI flags.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub config_path: Option<PathBuf>,
+    pub mac: Option<String>,
+    pub audio_device: Option<String>,
+    pub pipe_path: Option<PathBuf>,
+    pub pipe_enabled: Option<bool>,
+    pub socket_path: Option<PathBuf>,
+}
+
+impl Overrides {
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Overrides {
+            config_path: var("LGTV_CONFIG").map(PathBuf::from),
+            mac: var("LGTV_MAC"),
+            audio_device: var("LGTV_AUDIO_DEVICE"),
+            pipe_path: var("LGTV_PIPE_PATH").map(PathBuf::from),
+            pipe_enabled: None,
+            socket_path: var("LGTV_SOCKET_PATH").map(PathBuf::from),
+        }
+    }
+
+    /// Pulls the config flags (`--flag value` / `--flag=value`) out of `args`. Returns the
+    /// overrides and the remaining arguments (subcommand and its flags), in order.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<(Self, Vec<String>), ConfigError> {
+        let mut overrides = Overrides::default();
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let flag_name = arg.split_once('=').map_or(arg.as_str(), |(f, _)| f);
+            if !CONFIG_FLAGS.contains(&flag_name) {
+                rest.push(arg);
+                continue;
+            }
+            if flag_name == "--no-pipe" {
+                if arg != "--no-pipe" {
+                    return Err(ConfigError::Usage("--no-pipe takes no value".to_string()));
+                }
+                overrides.pipe_enabled = Some(false);
+                continue;
+            }
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) => (f.to_string(), Some(v.to_string())),
+                None => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ConfigError::Usage(format!("missing value for {}", flag)))
+            };
+            match flag.as_str() {
+                "--config" => overrides.config_path = Some(PathBuf::from(value()?)),
+                "--mac" => overrides.mac = Some(value()?),
+                "--audio-device" => overrides.audio_device = Some(value()?),
+                "--pipe" => overrides.pipe_path = Some(PathBuf::from(value()?)),
+                "--socket" => overrides.socket_path = Some(PathBuf::from(value()?)),
+                _ => return Err(ConfigError::Usage(format!("unknown argument '{}'", arg))),
+            }
+        }
+        Ok((overrides, rest))
+    }
+
+    /// The config file these overrides point at, or the default one.
+    pub fn config_file(&self) -> PathBuf {
+        self.config_path
+            .clone()
+            .or_else(|| Overrides::from_env().config_path)
+            .unwrap_or_else(default_config_path)
+    }
+
+    /// Layers `other` on top of `self`.
+    fn merge(self, other: Overrides) -> Overrides {
+        Overrides {
+            config_path: other.config_path.or(self.config_path),
+            mac: other.mac.or(self.mac),
+            audio_device: other.audio_device.or(self.audio_device),
+            pipe_path: other.pipe_path.or(self.pipe_path),
+            pipe_enabled: other.pipe_enabled.or(self.pipe_enabled),
+            socket_path: other.socket_path.or(self.socket_path),
+        }
+    }
+
+    fn apply(self, config: &mut Config) {
+        if let Some(mac) = self.mac {
+            config.mac = mac;
+        }
+        if let Some(device) = self.audio_device {
+            config.audio_device = device;
+        }
+        if let Some(pipe) = self.pipe_path {
+            config.pipe_path = pipe;
+        }
+        if let Some(enabled) = self.pipe_enabled {
+            config.pipe_enabled = enabled;
+        }
+        if let Some(socket) = self.socket_path {
+            config.socket_path = socket;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Usage(String),
+    Read { path: PathBuf, source: std::io::Error },
+    Parse { path: PathBuf, message: String },
+    Invalid(Vec<FieldError>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Usage(msg) => write!(f, "{}", msg),
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "invalid config file {}: {}", path.display(), message)
+            }
+            ConfigError::Invalid(errors) => {
+                writeln!(f, "invalid configuration:")?;
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "  - {}: {}", e.field, e.reason)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Default location of the config file.
+pub fn default_config_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home_dir().expect("Cannot find home directory").join(".config"));
+    base.join("lgtv").join("config.toml")
+}
+
+impl Config {
+    /// Loads the config file and applies environment and CLI overrides, then validates the result.
+    pub fn load(cli: Overrides) -> Result<Config, ConfigError> {
+        let config = Config::resolve(cli)?;
+        config.validate(true)?;
+        Ok(config)
+    }
+
+    /// Like [`Config::load`], but leaves out the pipe and socket paths, which `--direct`
+    /// never opens.
+    pub fn load_direct(cli: Overrides) -> Result<Config, ConfigError> {
+        let config = Config::resolve(cli)?;
+        config.validate(false)?;
+        Ok(config)
+    }
+
+    /// Like [`Config::load`] but without validation, for client commands that only
+    /// need to know where the daemon listens.
+    pub fn resolve(cli: Overrides) -> Result<Config, ConfigError> {
+        let overrides = Overrides::from_env().merge(cli);
+
+        let mut config = match &overrides.config_path {
+            // An explicitly requested file must exist.
+            Some(path) => Config::from_file(path)?,
+            None => {
+                let path = default_config_path();
+                if path.exists() { Config::from_file(&path)? } else { Config::default() }
+            }
+        };
+
+        overrides.apply(&mut config);
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.message().to_string(),
+        })
+    }
+
+    /// Checks every setting; the pipe and socket paths only when `listening`.
+    fn validate(&self, listening: bool) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        let mac_re = Regex::new(r"^([0-9a-fA-F]{2}[:-]){5}[0-9a-fA-F]{2}$").unwrap();
+        if self.mac.is_empty() {
+            errors.push(FieldError {
+                field: "mac",
+                reason: "not set; add `mac = \"AA:BB:CC:DD:EE:FF\"` to the config file, or use --mac / LGTV_MAC".into(),
+            });
+        } else if !mac_re.is_match(&self.mac) {
+            errors.push(FieldError {
+                field: "mac",
+                reason: format!("'{}' is not a MAC address (expected AA:BB:CC:DD:EE:FF)", self.mac),
+            });
+        }
+
+        if self.audio_device.trim().is_empty() {
+            errors.push(FieldError { field: "audio_device", reason: "must not be empty".into() });
+        }
+        if !(1..=100).contains(&self.volume.step) {
+            errors.push(FieldError { field: "volume.step", reason: format!("{} is not between 1 and 100", self.volume.step) });
+        }
+        if self.volume.accel_window_ms > 0 && self.volume.accel_max_step < self.volume.step {
+            errors.push(FieldError {
+                field: "volume.accel_max_step",
+                reason: format!("{} is smaller than volume.step ({})", self.volume.accel_max_step, self.volume.step),
+            });
+        }
+        if self.volume.max_volume > 100 {
+            errors.push(FieldError { field: "volume.max_volume", reason: format!("{} is above 100", self.volume.max_volume) });
+        }
+        if self.connection.retry_initial_ms == 0 {
+            errors.push(FieldError { field: "connection.retry_initial_ms", reason: "must be greater than 0".into() });
+        }
+        for (field, value) in [
+            ("connection.connect_timeout_ms", self.connection.connect_timeout_ms),
+            ("connection.pair_timeout_ms", self.connection.pair_timeout_ms),
+            ("connection.command_timeout_ms", self.connection.command_timeout_ms),
+        ] {
+            if value == 0 {
+                errors.push(FieldError { field, reason: "must be greater than 0".into() });
+            }
+        }
+        if self.discovery.ssdp && self.discovery.ssdp_timeout_ms == 0 {
+            errors.push(FieldError { field: "discovery.ssdp_timeout_ms", reason: "must be greater than 0".into() });
+        }
+        if self.identity.uuid.is_some() && self.discovery.ssdp_timeout_ms == 0 {
+            errors.push(FieldError {
+                field: "discovery.ssdp_timeout_ms",
+                reason: "must be greater than 0 to check identity.uuid".into(),
+            });
+        }
+        if self.discovery.sweep {
+            if self.discovery.sweep_rate == 0 {
+                errors.push(FieldError { field: "discovery.sweep_rate", reason: "must be greater than 0".into() });
+            }
+            if self.discovery.sweep_timeout_ms == 0 {
+                errors.push(FieldError { field: "discovery.sweep_timeout_ms", reason: "must be greater than 0".into() });
+            }
+        }
+        if self.connection.retry_max_ms < self.connection.retry_initial_ms {
+            errors.push(FieldError {
+                field: "connection.retry_max_ms",
+                reason: format!(
+                    "{} is smaller than connection.retry_initial_ms ({})",
+                    self.connection.retry_max_ms, self.connection.retry_initial_ms
+                ),
+            });
+        }
+        if self.audio.backend == AudioBackend::Command && self.audio.command.is_empty() {
+            errors.push(FieldError {
+                field: "audio.command",
+                reason: "required when audio.backend = \"command\", e.g. [\"my-probe\", \"--name\"]".into(),
+            });
+        }
+
+        if listening
+            && self.pipe_enabled
+            && let Err(reason) = check_writable(&self.pipe_path)
+        {
+            errors.push(FieldError { field: "pipe_path", reason });
+        }
+        if listening && let Err(reason) = check_writable(&self.socket_path) {
+            errors.push(FieldError { field: "socket_path", reason });
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(ConfigError::Invalid(errors)) }
+    }
+
+    /// MAC address normalised to the lowercase, colon-separated, zero-padded form the
+    /// neighbour table is compared in.
+    pub fn normalized_mac(&self) -> String {
+        crate::discovery::normalize_mac(&self.mac).unwrap_or_else(|| self.mac.to_lowercase())
+    }
+}
+
+/// Sets the top-level `mac` in the config file at `path`, leaving the rest of the file,
+/// comments included, as it is. Creates the file if it doesn't exist.
+pub fn write_mac(path: &Path, mac: &str) -> std::io::Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let setting = format!("mac = \"{}\"", mac);
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    // Top-level keys are the ones before the first table header.
+    let top = lines.iter().position(|l| l.trim_start().starts_with('[')).unwrap_or(lines.len());
+    let key = Regex::new(r"^\s*mac\s*=").unwrap();
+    match lines[..top].iter().position(|l| key.is_match(l)) {
+        Some(i) => lines[i] = setting,
+        None => lines.insert(0, setting),
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, lines.join("\n") + "\n")
+}
+
+/// The path must either be writable already, or be creatable in a writable directory.
+fn check_writable(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return access(path, AccessFlags::W_OK)
+            .map_err(|e| format!("{} is not writable ({})", path.display(), e));
+    }
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(format!("directory {} does not exist", parent.display()));
+    }
+    access(parent, AccessFlags::W_OK)
+        .map_err(|e| format!("cannot create {} in {} ({})", path.display(), parent.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn usage(line: &str) -> String {
+        match Overrides::from_args(args(line)) {
+            Err(ConfigError::Usage(msg)) => msg,
+            other => panic!("expected a usage error for '{}', got {:?}", line, other.map(|(_, rest)| rest)),
+        }
+    }
+
+    /// A scratch directory for one test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lgtv-config-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn valid(dir: &Path) -> Config {
+        Config {
+            mac: "3C:F0:83:9E:6A:2C".into(),
+            pipe_path: dir.join("pipe"),
+            socket_path: dir.join("sock"),
+            ..Default::default()
+        }
+    }
+
+    /// Makes one field of a valid config invalid.
+    type Breakage = fn(&mut Config);
+
+    fn invalid_fields(config: &Config) -> Vec<&'static str> {
+        match config.validate(true) {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(errors)) => errors.iter().map(|e| e.field).collect(),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn parses_flags_with_and_without_equals() {
+        let (overrides, rest) =
+            Overrides::from_args(args("--mac aa:bb:cc:dd:ee:ff volume --socket=/run/lgtv.sock up --no-pipe")).unwrap();
+        assert_eq!(overrides.mac.as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(overrides.socket_path, Some(PathBuf::from("/run/lgtv.sock")));
+        assert_eq!(overrides.pipe_enabled, Some(false));
+        assert_eq!(overrides.pipe_path, None);
+        assert_eq!(rest, ["volume", "up"]);
+
+        // Only the first `=` separates the value.
+        let (overrides, _) = Overrides::from_args(args("--audio-device=LG=TV")).unwrap();
+        assert_eq!(overrides.audio_device.as_deref(), Some("LG=TV"));
+        // Unknown flags are left for the subcommand.
+        let (_, rest) = Overrides::from_args(args("--direct --timeout 3 mute")).unwrap();
+        assert_eq!(rest, ["--direct", "--timeout", "3", "mute"]);
+    }
+
+    #[test]
+    fn rejects_bad_flags() {
+        assert_eq!(usage("status --mac"), "missing value for --mac");
+        assert_eq!(usage("--config"), "missing value for --config");
+        assert_eq!(usage("--no-pipe=yes"), "--no-pipe takes no value");
+        assert_eq!(usage("--no-pipe="), "--no-pipe takes no value");
+    }
+
+    #[test]
+    fn cli_overrides_env_overrides_file() {
+        let dir = scratch("layering");
+        let file = dir.join("config.toml");
+        std::fs::write(&file, "mac = \"11:11:11:11:11:11\"\naudio_device = \"File\"\npipe_path = \"/file/pipe\"\n").unwrap();
+        let mut config = Config::from_file(&file).unwrap();
+
+        let env = Overrides {
+            audio_device: Some("Env".into()),
+            pipe_path: Some("/env/pipe".into()),
+            ..Default::default()
+        };
+        let (cli, _) = Overrides::from_args(args("--pipe /cli/pipe --no-pipe")).unwrap();
+        env.merge(cli).apply(&mut config);
+
+        assert_eq!(config.mac, "11:11:11:11:11:11");
+        assert_eq!(config.audio_device, "Env");
+        assert_eq!(config.pipe_path, PathBuf::from("/cli/pipe"));
+        assert!(!config.pipe_enabled);
+        assert_eq!(config.socket_path, PathBuf::from(DEFAULT_SOCKET_PATH));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_file() {
+        let dir = scratch("unknown");
+        let file = dir.join("config.toml");
+        std::fs::write(&file, "[volume]\nstpe = 2\n").unwrap();
+        assert!(matches!(Config::from_file(&file), Err(ConfigError::Parse { .. })));
+        assert!(matches!(Config::from_file(&dir.join("missing.toml")), Err(ConfigError::Read { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validates_each_field() {
+        let dir = scratch("validate");
+        assert_eq!(invalid_fields(&valid(&dir)), Vec::<&str>::new());
+
+        let cases: Vec<(&str, Breakage)> = vec![
+            ("mac", |c| c.mac.clear()),
+            ("mac", |c| c.mac = "3C:F0:83:9E:6A".into()),
+            ("audio_device", |c| c.audio_device = "  ".into()),
+            ("volume.step", |c| c.volume.step = 0),
+            ("volume.step", |c| c.volume.step = 101),
+            ("volume.max_volume", |c| c.volume.max_volume = 101),
+            ("connection.retry_initial_ms", |c| c.connection.retry_initial_ms = 0),
+            ("connection.connect_timeout_ms", |c| c.connection.connect_timeout_ms = 0),
+            ("connection.pair_timeout_ms", |c| c.connection.pair_timeout_ms = 0),
+            ("connection.command_timeout_ms", |c| c.connection.command_timeout_ms = 0),
+            ("connection.retry_max_ms", |c| c.connection.retry_max_ms = c.connection.retry_initial_ms - 1),
+            ("discovery.ssdp_timeout_ms", |c| c.discovery.ssdp_timeout_ms = 0),
+            ("discovery.sweep_rate", |c| {
+                c.discovery.sweep = true;
+                c.discovery.sweep_rate = 0;
+            }),
+            ("discovery.sweep_timeout_ms", |c| {
+                c.discovery.sweep = true;
+                c.discovery.sweep_timeout_ms = 0;
+            }),
+            ("audio.command", |c| c.audio.backend = AudioBackend::Command),
+            ("pipe_path", |c| c.pipe_path = "/nonexistent/dir/pipe".into()),
+            ("socket_path", |c| c.socket_path = "/nonexistent/dir/sock".into()),
+        ];
+        for (field, break_it) in cases {
+            let mut config = valid(&dir);
+            break_it(&mut config);
+            assert_eq!(invalid_fields(&config), [field], "breaking {}", field);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn validates_dependent_settings() {
+        let dir = scratch("dependent");
+
+        // accel_max_step only matters with acceleration on.
+        let mut config = valid(&dir);
+        config.volume.step = 4;
+        config.volume.accel_max_step = 2;
+        assert_eq!(invalid_fields(&config), Vec::<&str>::new());
+        config.volume.accel_window_ms = 200;
+        assert_eq!(invalid_fields(&config), ["volume.accel_max_step"]);
+
+        // A disabled pipe isn't checked, and ssdp_timeout_ms only matters when it is used.
+        let mut config = valid(&dir);
+        config.pipe_enabled = false;
+        config.pipe_path = "/nonexistent/dir/pipe".into();
+        config.discovery.ssdp = false;
+        config.discovery.ssdp_timeout_ms = 0;
+        assert_eq!(invalid_fields(&config), Vec::<&str>::new());
+        config.identity.uuid = Some("4d1f0b1c".into());
+        assert_eq!(invalid_fields(&config), ["discovery.ssdp_timeout_ms"]);
+
+        // `--direct` doesn't listen, so doesn't need either path.
+        let mut config = valid(&dir);
+        config.pipe_path = "/nonexistent/dir/pipe".into();
+        config.socket_path = "/nonexistent/dir/sock".into();
+        assert_eq!(invalid_fields(&config), ["pipe_path", "socket_path"]);
+        assert!(config.validate(false).is_ok());
+
+        // Every problem is reported at once.
+        let mut config = valid(&dir);
+        config.mac.clear();
+        config.volume.step = 0;
+        assert_eq!(invalid_fields(&config), ["mac", "volume.step"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_mac_into_existing_file() {
+        let dir = scratch("write-mac");
+        let file = dir.join("config.toml");
+
+        std::fs::write(&file, "# my TV\nmac = \"11:11:11:11:11:11\"\naudio_device = \"LG\"\n\n[audio]\nbackend = \"always\"\n").unwrap();
+        write_mac(&file, "3c:f0:83:9e:6a:2c").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "# my TV\nmac = \"3c:f0:83:9e:6a:2c\"\naudio_device = \"LG\"\n\n[audio]\nbackend = \"always\"\n"
+        );
+
+        // A `mac` inside a table isn't the top-level one.
+        std::fs::write(&file, "[identity]\nmodel = \"OLED55C9PLA\"\n\n[extra]\nmac = \"keep\"\n").unwrap();
+        write_mac(&file, "3c:f0:83:9e:6a:2c").unwrap();
+        let text = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(text, "mac = \"3c:f0:83:9e:6a:2c\"\n[identity]\nmodel = \"OLED55C9PLA\"\n\n[extra]\nmac = \"keep\"\n");
+
+        let created = dir.join("new").join("config.toml");
+        write_mac(&created, "3c:f0:83:9e:6a:2c").unwrap();
+        let config = Config::from_file(&created).unwrap();
+        assert_eq!(config.mac, "3c:f0:83:9e:6a:2c");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
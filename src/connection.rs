@@ -0,0 +1,505 @@
+//! TV connection: locating the TV, connecting and pairing, and sending commands.
+//!
+//! `lg_webos_client` only does request/response for its own `Command`s. To also subscribe to
+//! updates, the client is given a channel-backed sink and a tapped stream: extra SSAP messages
+//! go out through the same channel, and every incoming message is broadcast to whoever listens.
+//!
+//! The library panics if an answer arrives after its caller stopped waiting. So commands wait
+//! in a task of their own that outlives a timeout, and a timeout closes the connection; the
+//! task only gives up once the library has stopped reading.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::channel::mpsc::{SendError, UnboundedSender, unbounded};
+use futures_util::sink::SinkMapErr;
+use futures_util::{SinkExt, StreamExt};
+use home::home_dir;
+use lg_webos_client::client::{WebOsClientConfig, WebosClient};
+use lg_webos_client::command::{Command as WebOsCommand, CommandResponse};
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::command::TvCommand;
+use crate::config::{ConnectionConfig, DiscoveryConfig, IdentityConfig, VolumeConfig};
+use crate::discovery;
+use crate::error::LgtvError;
+use crate::identity;
+use crate::volume::{self, SharedVolume, VolumeState};
+
+type RawSink = SinkMapErr<UnboundedSender<Message>, fn(SendError) -> WsError>;
+
+pub type ClientType = WebosClient<RawSink>;
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    /// Looking the MAC address up in the neighbour table, or searching with SSDP.
+    Resolving,
+    /// Opening the WebSocket to the TV at this address.
+    Connecting(String),
+    /// Registering with the pairing key; the TV may be showing a prompt.
+    Pairing(String),
+    Connected(String),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+            ConnectionState::Resolving => write!(f, "resolving"),
+            ConnectionState::Connecting(ip) => write!(f, "connecting to {}", ip),
+            ConnectionState::Pairing(ip) => write!(f, "pairing with {}", ip),
+            ConnectionState::Connected(ip) => write!(f, "connected to {}", ip),
+        }
+    }
+}
+
+/// A paired connection to the TV.
+pub struct TvConnection {
+    pub client: Arc<ClientType>,
+    /// Volume/mute model, kept current by the volume subscription.
+    pub volume: SharedVolume,
+    raw: UnboundedSender<Message>,
+    messages: broadcast::Sender<Value>,
+    /// Turns false once the socket has closed in either direction and nothing more is read.
+    alive: watch::Receiver<bool>,
+    /// Set when a timeout closed the socket, rather than the TV.
+    timed_out: AtomicBool,
+    next_id: AtomicU64,
+    /// How long a command may wait for its answer.
+    command_timeout: Duration,
+}
+
+impl TvConnection {
+    /// Every message the TV sends on this connection, from now on.
+    pub fn messages(&self) -> broadcast::Receiver<Value> {
+        self.messages.subscribe()
+    }
+
+    /// Sends a library command. Fails if the socket closes or the TV takes longer than the
+    /// command timeout, both of which the library itself would wait out forever. A timeout
+    /// closes the connection.
+    pub async fn send(&self, command: WebOsCommand) -> Result<CommandResponse, LgtvError> {
+        let client = self.client.clone();
+        let mut alive = self.alive.clone();
+        // Keeps waiting after a timeout, until the reader stops, so a late answer still has
+        // somewhere to go.
+        let answer = tokio::spawn(async move {
+            tokio::select! {
+                resp = client.send_command(command) => resp.map_err(|_| LgtvError::Closed),
+                _ = alive.wait_for(|alive| !alive) => Err(LgtvError::Closed),
+            }
+        });
+        let resp = match timeout(self.command_timeout, answer).await {
+            Ok(joined) => joined.map_err(|_| LgtvError::Closed)??,
+            Err(_) => return Err(self.time_out()),
+        };
+
+        // webOS reports failed requests as `returnValue: false` with an `errorText`.
+        if let Some(payload) = &resp.payload
+            && payload.get("returnValue").and_then(Value::as_bool) == Some(false)
+        {
+            let reason = payload.get("errorText").and_then(Value::as_str).unwrap_or("request failed");
+            return Err(LgtvError::Rejected(reason.to_string()));
+        }
+        Ok(resp)
+    }
+
+    /// Resolves once the socket has closed, e.g. because the TV was turned off.
+    pub async fn closed(&self) {
+        let mut alive = self.alive.clone();
+        let _ = alive.wait_for(|alive| !alive).await;
+    }
+
+    /// Whether the socket was closed because the TV didn't answer in time.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Closes the socket after the TV failed to answer; [`TvConnection::closed`] resolves
+    /// once nothing more is read.
+    fn time_out(&self) -> LgtvError {
+        self.timed_out.store(true, Ordering::Relaxed);
+        self.raw.close_channel();
+        LgtvError::Timeout { action: "answer", after: self.command_timeout }
+    }
+
+    /// Sends an SSAP message of type `kind` for `uri`, returning its ID.
+    fn send_raw(&self, kind: &str, uri: &str) -> Result<String, LgtvError> {
+        // The library numbers its requests 1, 2, 3...; prefixed IDs can't collide.
+        let id = format!("lgtv-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let message = json!({ "id": id, "type": kind, "uri": uri });
+        self.raw
+            .unbounded_send(Message::Text(message.to_string()))
+            .map_err(|_| LgtvError::Closed)?;
+        Ok(id)
+    }
+
+    /// Subscribes to `uri`. Updates arrive on [`TvConnection::messages`] with the returned ID.
+    pub fn subscribe(&self, uri: &str) -> Result<String, LgtvError> {
+        self.send_raw("subscribe", uri)
+    }
+
+    /// Requests `uri`, which the library has no `Command` for, and returns the payload. Fails,
+    /// and closes on a timeout, like [`TvConnection::send`].
+    pub async fn request(&self, uri: &str) -> Result<Value, LgtvError> {
+        let mut messages = self.messages();
+        let id = self.send_raw("request", uri)?;
+        let answer = async {
+            loop {
+                tokio::select! {
+                    msg = messages.recv() => match msg {
+                        Ok(msg) if msg.get("id").and_then(Value::as_str) == Some(id.as_str()) => return Ok(msg),
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return Err(LgtvError::Closed),
+                    },
+                    _ = self.closed() => return Err(LgtvError::Closed),
+                }
+            }
+        };
+        let msg = timeout(self.command_timeout, answer).await.map_err(|_| self.time_out())??;
+
+        if msg.get("type").and_then(Value::as_str) == Some("error") {
+            let reason = msg.get("error").and_then(Value::as_str).unwrap_or("request failed");
+            return Err(LgtvError::Rejected(reason.to_string()));
+        }
+        let payload = msg.get("payload").cloned().unwrap_or(Value::Null);
+        if payload.get("returnValue").and_then(Value::as_bool) == Some(false) {
+            let reason = payload.get("errorText").and_then(Value::as_str).unwrap_or("request failed");
+            return Err(LgtvError::Rejected(reason.to_string()));
+        }
+        Ok(payload)
+    }
+}
+
+fn closed(_: SendError) -> WsError {
+    WsError::ConnectionClosed
+}
+
+/// Where the pairing key is persisted between runs.
+pub fn key_path() -> PathBuf {
+    home_dir().expect("Cannot find home").join(".lgtv_key")
+}
+
+/// Reads the stored pairing key, unwrapping the JSON payload older versions saved.
+pub async fn load_key() -> Option<String> {
+    let key_path = key_path();
+    let mut key = if key_path.exists() {
+        tokio::fs::read_to_string(&key_path).await.ok().map(|k| k.trim().to_string())
+    } else { None };
+
+    // Extract key from JSON if needed
+    if let Some(ref k) = key
+        && let Ok(v) = serde_json::from_str::<serde_json::Value>(k)
+        && let Some(ck) = v.get("client-key").and_then(|v| v.as_str())
+    {
+        key = Some(ck.to_string());
+    }
+    key
+}
+
+/// Opens the WebSocket to the TV at `ip`, without registering yet.
+pub async fn open(ip: &str, config: &ConnectionConfig) -> Result<WsStream, LgtvError> {
+    let url = format!("ws://{}:3000/", ip);
+    let after = Duration::from_millis(config.connect_timeout_ms);
+    let (ws, _) = timeout(after, tokio_tungstenite::connect_async(url.as_str()))
+        .await
+        .map_err(|_| LgtvError::Timeout { action: "accept the connection", after })?
+        .map_err(|e| match e {
+            WsError::Io(e) => LgtvError::Unreachable(e.to_string()),
+            other => LgtvError::Protocol(format!("WebSocket handshake failed: {}", other)),
+        })?;
+    Ok(ws)
+}
+
+/// Finds the TV with this MAC address, connects and pairs, and checks that it is the
+/// configured TV; returns the connection and the TV's address. The address the TV had last
+/// time is tried first. Every state entered on the way is passed to `on_state`, with the
+/// reason for going back to Resolving if the last known address doesn't work out.
+///
+/// The pairing key and the address are only saved once the TV has passed verification.
+/// After a [`LgtvError::WrongDevice`] the saved address is forgotten.
+pub async fn connect_verified(
+    mac: &str,
+    config: &ConnectionConfig,
+    discovery: &DiscoveryConfig,
+    identity: &IdentityConfig,
+    mut on_state: impl FnMut(ConnectionState, Option<&str>),
+) -> Result<(TvConnection, String), LgtvError> {
+    on_state(ConnectionState::Resolving, None);
+    let (ip, ws) = match open_last_known(mac, config, &mut on_state).await {
+        Some(opened) => opened,
+        None => {
+            let ip = discovery::resolve(mac, discovery).await?;
+            on_state(ConnectionState::Connecting(ip.clone()), None);
+            let ws = open(&ip, config).await?;
+            (ip, ws)
+        }
+    };
+
+    let wait = Duration::from_millis(discovery.ssdp_timeout_ms);
+    let verified = async {
+        identity::verify_uuid(&ip, identity, wait).await?;
+        on_state(ConnectionState::Pairing(ip.clone()), None);
+        let (tv, new_key) = pair(ws, config).await?;
+        identity::verify(&tv, &ip, identity).await?;
+        Ok((tv, new_key))
+    };
+    let (tv, new_key) = match verified.await {
+        Ok(paired) => paired,
+        Err(e) => {
+            // The same device would most likely be found at that address again.
+            if let LgtvError::WrongDevice(_) = e {
+                discovery::forget();
+            }
+            return Err(e);
+        }
+    };
+
+    // Only now is the key known to belong to the configured TV.
+    if let Some(key) = new_key {
+        save_key(&key).await;
+    }
+    discovery::remember(mac, &ip);
+    Ok((tv, ip))
+}
+
+/// Tries the address the TV had last time, skipping discovery. Returns `None`, back in
+/// Resolving, if nothing answers there; if another device has taken the address, it is
+/// forgotten as well.
+async fn open_last_known(
+    mac: &str,
+    config: &ConnectionConfig,
+    on_state: &mut impl FnMut(ConnectionState, Option<&str>),
+) -> Option<(String, WsStream)> {
+    let ip = discovery::last_known(mac)?;
+    on_state(ConnectionState::Connecting(ip.clone()), Some("last known address"));
+    let reason = match open(&ip, config).await {
+        Ok(ws) => match discovery::check_neighbor(&ip, mac) {
+            Ok(()) => return Some((ip, ws)),
+            Err(e) => {
+                discovery::forget();
+                e.to_string()
+            }
+        },
+        Err(e) => e.to_string(),
+    };
+    on_state(ConnectionState::Resolving, Some(&format!("{}; rediscovering", reason)));
+    None
+}
+
+/// Stores a pairing key returned by [`pair`].
+pub async fn save_key(key: &str) {
+    if let Err(e) = tokio::fs::write(key_path(), key.as_bytes()).await {
+        eprintln!("Cannot store the pairing key in {}: {}", key_path().display(), e);
+    }
+}
+
+/// Registers on an open socket with the stored key (the TV prompts if there is none), then
+/// seeds and subscribes to the volume model. Also returns the key the TV handed out if it
+/// differs from the stored one; it is not saved here, since the device may yet turn out not
+/// to be the configured TV.
+pub async fn pair(ws: WsStream, config: &ConnectionConfig) -> Result<(TvConnection, Option<String>), LgtvError> {
+    let key = load_key().await;
+    // The URL is only used by `WebosClient::new`, which opens its own socket.
+    let client_config = WebOsClientConfig::new("ws://localhost:3000/", key.clone());
+    let (ws_write, ws_read) = ws.split();
+    let (alive, alive_rx) = watch::channel(true);
+
+    // Outgoing: the client and our own subscriptions share one channel into the socket.
+    let (raw, raw_rx) = unbounded::<Message>();
+    let (writer_done, writer_stopped) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let _ = raw_rx.map(Ok).forward(ws_write).await;
+        drop(writer_done);
+    });
+
+    // Incoming: the client sees everything, and so does anyone subscribed to `messages`.
+    // Reading stops with the writer, and the chained stream is only polled once it has.
+    let (messages, _) = broadcast::channel(64);
+    let tap = messages.clone();
+    let read = ws_read
+        .take_until(writer_stopped)
+        .inspect(move |msg| {
+            if let Ok(Message::Text(text)) = msg
+                && let Ok(value) = serde_json::from_str::<Value>(text)
+            {
+                let _ = tap.send(value);
+            }
+        })
+        .chain(futures::stream::poll_fn(move |_| {
+            alive.send_replace(false);
+            Poll::Ready(None)
+        }));
+
+    let sink: RawSink = raw.clone().sink_map_err(closed);
+    let after = Duration::from_millis(config.pair_timeout_ms);
+    let c = timeout(after, WebosClient::from_stream_and_sink(read, sink, client_config))
+        .await
+        .map_err(|_| LgtvError::Timeout { action: "finish pairing", after })?
+        .map_err(|_| LgtvError::Closed)?;
+
+    // The library hands back the registration payload; without a key the prompt was declined.
+    let registered = c.key.as_deref().and_then(|k| serde_json::from_str::<Value>(k).ok());
+    if registered.as_ref().and_then(|p| p.get("client-key")).is_none() {
+        return Err(LgtvError::Pairing("the prompt on the TV was declined".to_string()));
+    }
+
+    let new_key = c.key.as_deref().map(str::trim).filter(|k| key.as_deref() != Some(*k)).map(str::to_string);
+
+    let tv = TvConnection {
+        client: Arc::new(c),
+        volume: Arc::new(Mutex::new(VolumeState::default())),
+        raw,
+        messages,
+        alive: alive_rx,
+        timed_out: AtomicBool::new(false),
+        next_id: AtomicU64::new(1),
+        command_timeout: Duration::from_millis(config.command_timeout_ms),
+    };
+
+    let resp = tv.send(WebOsCommand::GetVolume).await?;
+    if let Some(payload) = &resp.payload {
+        *tv.volume.lock().unwrap() = VolumeState::from_payload(payload);
+    }
+    if let Err(e) = volume::spawn_watcher(&tv) {
+        eprintln!("Volume subscription failed, remote changes won't be tracked: {}", e);
+    }
+    Ok((tv, new_key))
+}
+
+/// Moves the volume by `delta` with a single absolute `SetVolume`, however many key presses
+/// the delta stands for, never going above `max_volume`.
+pub async fn adjust_volume(tv: &TvConnection, delta: i64, max_volume: u8) -> Result<Option<Value>, LgtvError> {
+    match target_volume(tv, delta, max_volume).await? {
+        Some(target) => set_volume(tv, target).await,
+        None => Ok(None),
+    }
+}
+
+/// The absolute volume `delta` leads to, from the tracked model when known and otherwise
+/// asked from the TV. `None` if the TV doesn't report its volume.
+pub async fn target_volume(tv: &TvConnection, delta: i64, max_volume: u8) -> Result<Option<u8>, LgtvError> {
+    let known = tv.volume.lock().unwrap().volume;
+    let current = match known {
+        Some(v) => v,
+        None => {
+            let resp = tv.send(WebOsCommand::GetVolume).await?;
+            match resp.payload.as_ref().map(VolumeState::from_payload).and_then(|s| s.volume) {
+                Some(v) => v,
+                None => return Ok(None),
+            }
+        }
+    };
+    Ok(Some(volume_target(current as i64, delta, max_volume)))
+}
+
+/// Where `delta` takes the volume from `current`, never above `max_volume`. A volume already
+/// above the cap (set on the remote) may still be turned down.
+pub fn volume_target(current: i64, delta: i64, max_volume: u8) -> u8 {
+    let ceiling = (max_volume as i64).max(if delta < 0 { current } else { 0 });
+    (current + delta).clamp(0, ceiling) as u8
+}
+
+/// Sets an absolute volume that has already been checked against the cap.
+pub async fn set_volume(tv: &TvConnection, volume: u8) -> Result<Option<Value>, LgtvError> {
+    let resp = tv.send(WebOsCommand::SetVolume(volume as i8)).await?;
+    tv.volume.lock().unwrap().volume = Some(volume);
+    Ok(resp.payload)
+}
+
+/// Current mute state, from the tracked model or else asked from the TV.
+async fn is_muted(tv: &TvConnection) -> Result<bool, LgtvError> {
+    let known = tv.volume.lock().unwrap().muted;
+    if let Some(muted) = known {
+        return Ok(muted);
+    }
+    let resp = tv.send(WebOsCommand::GetVolume).await?;
+    let state = resp.payload.as_ref().map(VolumeState::from_payload).unwrap_or_default();
+    tv.volume.lock().unwrap().merge(state);
+    state.muted.ok_or_else(|| LgtvError::Protocol("no mute state in the volume response".to_string()))
+}
+
+/// Sends a single command to the TV and returns the TV's response payload.
+pub async fn execute(tv: &TvConnection, command: &TvCommand, volume: &VolumeConfig) -> Result<Option<Value>, LgtvError> {
+    let toggled;
+    let command = match command {
+        TvCommand::MuteToggle => {
+            toggled = if is_muted(tv).await? { TvCommand::Unmute } else { TvCommand::Mute };
+            &toggled
+        }
+        other => other,
+    };
+    let webos_command = match command {
+        TvCommand::VolumeUp(_) | TvCommand::VolumeDown(_) => {
+            let (direction, step) = command.volume_step().unwrap_or_default();
+            let delta = direction * step.unwrap_or(volume.step) as i64;
+            return adjust_volume(tv, delta, volume.max_volume).await;
+        }
+        TvCommand::Mute => WebOsCommand::SetMute(true),
+        TvCommand::Unmute => WebOsCommand::SetMute(false),
+        TvCommand::MuteToggle => unreachable!("resolved to Mute or Unmute above"),
+        TvCommand::GetVolume => WebOsCommand::GetVolume,
+        TvCommand::SetVolume(v) => WebOsCommand::SetVolume((*v).min(volume.max_volume) as i8),
+        TvCommand::PowerOff => WebOsCommand::TurnOff,
+        TvCommand::Input(id) => WebOsCommand::SwitchInput(id.clone()),
+        TvCommand::Launch(id) => WebOsCommand::Launch(id.clone(), json!({})),
+        TvCommand::ChannelUp => WebOsCommand::ChannelUp,
+        TvCommand::ChannelDown => WebOsCommand::ChannelDown,
+        TvCommand::Play => WebOsCommand::PlayMedia,
+        TvCommand::Pause => WebOsCommand::PauseMedia,
+        TvCommand::Stop => WebOsCommand::StopMedia,
+        TvCommand::Rewind => WebOsCommand::RewindMedia,
+        TvCommand::Forward => WebOsCommand::ForwardMedia,
+        TvCommand::Toast(msg) => WebOsCommand::CreateToast(msg.clone()),
+    };
+    let resp = tv.send(webos_command).await?;
+
+    // Keep the model in step with our own writes; the subscription confirms them later.
+    let mut state = tv.volume.lock().unwrap();
+    match command {
+        TvCommand::SetVolume(v) => state.volume = Some((*v).min(volume.max_volume)),
+        TvCommand::Mute => state.muted = Some(true),
+        TvCommand::Unmute => state.muted = Some(false),
+        TvCommand::GetVolume => {
+            if let Some(payload) = &resp.payload {
+                state.merge(VolumeState::from_payload(payload));
+            }
+        }
+        _ => {}
+    }
+    Ok(resp.payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_target_stays_in_range() {
+        assert_eq!(volume_target(10, 5, 100), 15);
+        assert_eq!(volume_target(3, -5, 100), 0);
+        assert_eq!(volume_target(98, 5, 100), 100);
+        assert_eq!(volume_target(25, 10, 30), 30);
+    }
+
+    #[test]
+    fn volume_above_cap_can_still_go_down() {
+        // Set on the remote, above `max_volume`.
+        assert_eq!(volume_target(50, -1, 30), 49);
+        assert_eq!(volume_target(50, -30, 30), 20);
+        // Going up brings it back to the cap.
+        assert_eq!(volume_target(50, 1, 30), 30);
+    }
+}
@@ -0,0 +1,419 @@
+//! Finding the TV's IP address from its MAC address.
+//!
+//! The kernel's neighbour (ARP) table is read from whichever source the platform has:
+//! - Linux: `/proc/net/arp`, then `ip neigh` (which also lists IPv6 neighbours).
+//! - macOS / BSD: `arp -an`. Linux `net-tools` `arp -an` prints a close enough format.
+//!
+//! A TV this machine hasn't talked to yet is not in the table. [`resolve`] then falls back to
+//! an SSDP search (`ssdp.rs`), which gets every TV on the LAN to answer, and optionally to a
+//! sweep of the local subnet (`sweep.rs`).
+//!
+//! The TV's address rarely changes, so the last one that worked is stored next to the pairing
+//! key and tried before any of this.
+//!
+//! [`discover`] runs every method, mDNS (`mdns.rs`) included, for `lgtv discover`.
+
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use futures::future::join_all;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::config::DiscoveryConfig;
+use crate::connection::key_path;
+use crate::error::LgtvError;
+use crate::{mdns, ssdp, sweep};
+
+/// One entry of the neighbour table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    pub ip: String,
+    /// Lowercase, colon-separated, zero-padded.
+    pub mac: String,
+}
+
+/// Normalises a MAC address to `aa:bb:cc:dd:ee:ff`. Accepts `-` separators and the
+/// unpadded octets macOS prints (`0:11:22:3:44:55`).
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let octets: Vec<&str> = mac.split([':', '-']).collect();
+    if octets.len() != 6 {
+        return None;
+    }
+    let mut normalized = Vec::with_capacity(6);
+    for octet in octets {
+        let value = u8::from_str_radix(octet, 16).ok().filter(|_| (1..=2).contains(&octet.len()))?;
+        normalized.push(format!("{:02x}", value));
+    }
+    Some(normalized.join(":"))
+}
+
+fn neighbor(ip: &str, mac: &str) -> Option<Neighbor> {
+    let mac = normalize_mac(mac)?;
+    // Incomplete entries show up as all zeros in /proc/net/arp.
+    (mac != "00:00:00:00:00:00").then(|| Neighbor { ip: ip.to_string(), mac })
+}
+
+/// Parses BSD/macOS `arp -an` output:
+/// `? (192.168.1.20) at 3c:f0:83:9e:6a:2c on en0 ifscope [ethernet]`.
+pub fn parse_arp(output: &str) -> Vec<Neighbor> {
+    let re = Regex::new(r"\(([^)]+)\) at ([0-9a-fA-F:-]+)").expect("valid regex");
+    output
+        .lines()
+        .filter_map(|line| re.captures(line))
+        .filter_map(|caps| neighbor(&caps[1], &caps[2]))
+        .collect()
+}
+
+/// Parses Linux `/proc/net/arp`:
+/// `192.168.1.20  0x1  0x2  3c:f0:83:9e:6a:2c  *  wlan0`, after a header line.
+pub fn parse_proc_net_arp(text: &str) -> Vec<Neighbor> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [ip, _hw_type, flags, mac, ..] = fields.as_slice() else { return None };
+            // ATF_COM (0x2) marks a completed entry.
+            let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+            if flags & 0x2 == 0 {
+                return None;
+            }
+            neighbor(ip, mac)
+        })
+        .collect()
+}
+
+/// Parses `ip neigh` output:
+/// `192.168.1.20 dev wlan0 lladdr 3c:f0:83:9e:6a:2c REACHABLE`. Entries without a link-layer
+/// address (`INCOMPLETE`, `FAILED`) are skipped.
+pub fn parse_ip_neigh(output: &str) -> Vec<Neighbor> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = fields.first()?;
+            let mac = fields.iter().position(|f| *f == "lladdr").and_then(|i| fields.get(i + 1))?;
+            neighbor(ip, mac)
+        })
+        .collect()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let out = Command::new(program).args(args).output().map_err(|e| format!("{}: {}", program, e))?;
+    if !out.status.success() {
+        return Err(format!("{} failed ({})", program, out.status));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+type Source = fn() -> Result<Vec<Neighbor>, String>;
+
+/// The neighbour table sources, in the order they are tried.
+const SOURCES: &[(&str, Source)] = &[
+    ("/proc/net/arp", || {
+        let path = Path::new("/proc/net/arp");
+        std::fs::read_to_string(path).map(|t| parse_proc_net_arp(&t)).map_err(|e| format!("{}: {}", path.display(), e))
+    }),
+    ("ip neigh", || run("ip", &["neigh", "show"]).map(|o| parse_ip_neigh(&o))),
+    ("arp -an", || run("arp", &["-an"]).map(|o| parse_arp(&o))),
+];
+
+/// Every neighbour from every source that can be read, first source winning.
+pub fn neighbors() -> Vec<Neighbor> {
+    let mut all: Vec<Neighbor> = Vec::new();
+    for (_, read) in SOURCES {
+        for entry in read().unwrap_or_default() {
+            if !all.iter().any(|n| n.ip == entry.ip) {
+                all.push(entry);
+            }
+        }
+    }
+    all
+}
+
+/// Resolves the IPv4 address of the LG TV using its MAC address, stopping at the first
+/// source that knows it.
+pub fn resolve_ip_from_mac(target_mac: &str) -> Result<String, LgtvError> {
+    let target = normalize_mac(target_mac)
+        .ok_or_else(|| LgtvError::Discovery(format!("'{}' is not a MAC address", target_mac)))?;
+
+    let mut errors = Vec::new();
+    for (_, read) in SOURCES {
+        match read() {
+            Ok(entries) => {
+                if let Some(entry) = entries.into_iter().find(|n| n.mac == target && n.ip.parse::<Ipv4Addr>().is_ok()) {
+                    return Ok(entry.ip);
+                }
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.len() == SOURCES.len() {
+        return Err(LgtvError::Discovery(format!("cannot read the neighbour table ({})", errors.join("; "))));
+    }
+    let searched: Vec<&str> = SOURCES.iter().map(|(name, _)| *name).collect();
+    Err(LgtvError::Discovery(format!("{} is not in the neighbour table ({})", target_mac, searched.join(", "))))
+}
+
+/// The bare reason of a discovery error, for joining with others into one.
+fn reason(e: LgtvError) -> String {
+    match e {
+        LgtvError::Discovery(reason) => reason,
+        other => other.to_string(),
+    }
+}
+
+/// Finds the TV's IP address: from the neighbour table, or else with an SSDP search, or
+/// else, if enabled, by sweeping the local subnet. Both put the hosts they talk to in the
+/// neighbour table, so it is read once more after each to pick the one with this MAC.
+pub async fn resolve(target_mac: &str, config: &DiscoveryConfig) -> Result<String, LgtvError> {
+    let mut reasons = match resolve_ip_from_mac(target_mac) {
+        Ok(ip) => return Ok(ip),
+        Err(e) => vec![reason(e)],
+    };
+
+    if config.ssdp {
+        match ssdp::search(ssdp::MULTICAST, Duration::from_millis(config.ssdp_timeout_ms)).await {
+            Ok(tvs) if tvs.is_empty() => reasons.push("no TV answered an SSDP search".to_string()),
+            Ok(tvs) => {
+                if let Ok(ip) = resolve_ip_from_mac(target_mac) {
+                    return Ok(ip);
+                }
+                let ips: Vec<&str> = tvs.iter().map(|tv| tv.ip.as_str()).collect();
+                reasons.push(format!("SSDP found TVs at {}, but none with this MAC", ips.join(", ")));
+            }
+            Err(e) => reasons.push(reason(e)),
+        }
+    }
+
+    if config.sweep {
+        match sweep::sweep(config.sweep_rate, Duration::from_millis(config.sweep_timeout_ms)).await {
+            Ok(probed) => {
+                if let Ok(ip) = resolve_ip_from_mac(target_mac) {
+                    return Ok(ip);
+                }
+                reasons.push(format!("still missing after probing {} addresses on the local subnet", probed));
+            }
+            Err(e) => reasons.push(reason(e)),
+        }
+    }
+    Err(LgtvError::Discovery(reasons.join("; ")))
+}
+
+/// The address the TV last had, and the MAC address it was found by.
+#[derive(Serialize, Deserialize)]
+struct LastKnown {
+    mac: String,
+    ip: String,
+}
+
+/// Where the last known address is persisted, next to the pairing key.
+fn last_known_path() -> PathBuf {
+    key_path().with_file_name(".lgtv_last_ip")
+}
+
+/// The address the TV with this MAC had last time, if it was stored for the same MAC.
+pub fn last_known(mac: &str) -> Option<String> {
+    let text = std::fs::read_to_string(last_known_path()).ok()?;
+    let stored: LastKnown = serde_json::from_str(&text).ok()?;
+    (normalize_mac(&stored.mac)? == normalize_mac(mac)?).then_some(stored.ip)
+}
+
+/// Stores the address the TV was just reached at.
+pub fn remember(mac: &str, ip: &str) {
+    if last_known(mac).as_deref() == Some(ip) {
+        return;
+    }
+    let stored = LastKnown { mac: normalize_mac(mac).unwrap_or_else(|| mac.to_string()), ip: ip.to_string() };
+    let text = serde_json::to_string(&stored).expect("LastKnown is always serialisable");
+    if let Err(e) = std::fs::write(last_known_path(), text) {
+        eprintln!("Cannot store the TV's address in {}: {}", last_known_path().display(), e);
+    }
+}
+
+pub fn forget() {
+    let _ = std::fs::remove_file(last_known_path());
+}
+
+/// Checks that `ip` still belongs to the TV with this MAC, once something has been sent to
+/// it, so a device that took over the TV's old DHCP lease isn't mistaken for it. Passes if
+/// the neighbour table doesn't know `ip`.
+pub fn check_neighbor(ip: &str, mac: &str) -> Result<(), LgtvError> {
+    let expected = normalize_mac(mac);
+    match neighbors().into_iter().find(|n| n.ip == ip) {
+        Some(entry) if Some(&entry.mac) != expected.as_ref() => Err(LgtvError::Discovery(format!(
+            "{} now belongs to {}, not {}",
+            ip, entry.mac, mac
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A TV found by [`discover`].
+#[derive(Debug, Clone, Default)]
+pub struct Candidate {
+    pub ip: String,
+    pub mac: Option<String>,
+    pub model: Option<String>,
+    pub name: Option<String>,
+    /// The methods that found it: `ssdp`, `mdns`, `arp`.
+    pub found_by: Vec<&'static str>,
+}
+
+impl Candidate {
+    fn merge(list: &mut Vec<Candidate>, found: Candidate) {
+        let Some(seen) = list.iter_mut().find(|c| c.ip == found.ip) else { return list.push(found) };
+        seen.mac = seen.mac.take().or(found.mac);
+        seen.model = seen.model.take().or(found.model);
+        seen.name = seen.name.take().or(found.name);
+        for method in found.found_by {
+            if !seen.found_by.contains(&method) {
+                seen.found_by.push(method);
+            }
+        }
+    }
+}
+
+/// Finds every TV on the LAN with SSDP and mDNS, and the TV with `configured_mac` (if any) in
+/// the neighbour table, after a sweep if enabled. MAC addresses come from the neighbour
+/// table; TVs that aren't in it yet are sent a connection attempt on port 3000 first, which
+/// puts them there. Failing methods are reported as warnings next to whatever the others
+/// found.
+pub async fn discover(configured_mac: Option<&str>, config: &DiscoveryConfig) -> (Vec<Candidate>, Vec<LgtvError>) {
+    let wait = Duration::from_millis(config.ssdp_timeout_ms);
+    let (ssdp, mdns) = tokio::join!(ssdp::search(ssdp::MULTICAST, wait), mdns::browse(wait));
+
+    let mut found = Vec::new();
+    let mut warnings = Vec::new();
+    match ssdp {
+        Ok(tvs) => {
+            for tv in tvs {
+                let candidate = Candidate { ip: tv.ip, model: tv.model, name: tv.name, found_by: vec!["ssdp"], ..Default::default() };
+                Candidate::merge(&mut found, candidate);
+            }
+        }
+        Err(e) => warnings.push(e),
+    }
+    match mdns {
+        Ok(tvs) => {
+            for tv in tvs {
+                let candidate =
+                    Candidate { ip: tv.ip, mac: tv.mac, model: tv.model, name: Some(tv.name), found_by: vec!["mdns"] };
+                Candidate::merge(&mut found, candidate);
+            }
+        }
+        Err(e) => warnings.push(e),
+    }
+
+    if config.sweep
+        && let Err(e) = sweep::sweep(config.sweep_rate, Duration::from_millis(config.sweep_timeout_ms)).await
+    {
+        warnings.push(e);
+    }
+    let mut table = neighbors();
+    let missing: Vec<&Candidate> = found.iter().filter(|c| !table.iter().any(|n| n.ip == c.ip)).collect();
+    if !missing.is_empty() {
+        join_all(missing.iter().map(|c| timeout(Duration::from_millis(500), TcpStream::connect((c.ip.as_str(), 3000)))))
+            .await;
+        table = neighbors();
+    }
+    for candidate in &mut found {
+        if let Some(entry) = table.iter().find(|n| n.ip == candidate.ip) {
+            candidate.mac = Some(entry.mac.clone());
+            candidate.found_by.push("arp");
+        }
+    }
+    if let Some(mac) = configured_mac.and_then(normalize_mac)
+        && let Some(entry) = table.iter().find(|n| n.mac == mac && n.ip.parse::<Ipv4Addr>().is_ok())
+    {
+        let candidate = Candidate { ip: entry.ip.clone(), mac: Some(mac), found_by: vec!["arp"], ..Default::default() };
+        Candidate::merge(&mut found, candidate);
+    }
+    (found, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn n(ip: &str, mac: &str) -> Neighbor {
+        Neighbor { ip: ip.to_string(), mac: mac.to_string() }
+    }
+
+    #[test]
+    fn normalizes_mac_addresses() {
+        assert_eq!(normalize_mac("3C-F0-83-9E-6A-2C").as_deref(), Some("3c:f0:83:9e:6a:2c"));
+        assert_eq!(normalize_mac("0:11:22:3:44:55").as_deref(), Some("00:11:22:03:44:55"));
+        assert_eq!(normalize_mac("00:11:22:33:44"), None);
+        assert_eq!(normalize_mac("00:11:22:33:44:5g"), None);
+        assert_eq!(normalize_mac("000:11:22:33:44:55"), None);
+    }
+
+    #[test]
+    fn parses_macos_arp() {
+        let output = "\
+? (192.168.1.1) at 0:11:22:3:44:55 on en0 ifscope [ethernet]
+? (192.168.1.20) at 3c:f0:83:9e:6a:2c on en0 ifscope [ethernet]
+? (192.168.1.31) at (incomplete) on en0 ifscope [ethernet]
+? (224.0.0.251) at 1:0:5e:0:0:fb on en0 ifscope permanent [ethernet]
+";
+        assert_eq!(
+            parse_arp(output),
+            vec![
+                n("192.168.1.1", "00:11:22:03:44:55"),
+                n("192.168.1.20", "3c:f0:83:9e:6a:2c"),
+                n("224.0.0.251", "01:00:5e:00:00:fb"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_linux_net_tools_arp() {
+        let output = "\
+? (192.168.1.1) at 00:11:22:33:44:55 [ether] on wlan0
+? (192.168.1.20) at 3c:f0:83:9e:6a:2c [ether] on wlan0
+? (192.168.1.31) at <incomplete> on wlan0
+";
+        assert_eq!(
+            parse_arp(output),
+            vec![n("192.168.1.1", "00:11:22:33:44:55"), n("192.168.1.20", "3c:f0:83:9e:6a:2c")]
+        );
+    }
+
+    #[test]
+    fn parses_proc_net_arp() {
+        let text = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.20     0x1         0x2         3c:f0:83:9e:6a:2c     *        wlan0
+192.168.1.31     0x1         0x0         00:00:00:00:00:00     *        wlan0
+192.168.1.1      0x1         0x6         00:11:22:33:44:55     *        wlan0
+";
+        assert_eq!(
+            parse_proc_net_arp(text),
+            vec![n("192.168.1.20", "3c:f0:83:9e:6a:2c"), n("192.168.1.1", "00:11:22:33:44:55")]
+        );
+    }
+
+    #[test]
+    fn parses_ip_neigh() {
+        let output = "\
+192.168.1.20 dev wlan0 lladdr 3c:f0:83:9e:6a:2c REACHABLE
+192.168.1.31 dev wlan0  FAILED
+192.168.1.40 dev wlan0 INCOMPLETE
+192.168.1.1 dev wlan0 lladdr 00:11:22:33:44:55 router STALE
+fe80::3ef0:83ff:fe9e:6a2c dev wlan0 lladdr 3c:f0:83:9e:6a:2c STALE
+";
+        assert_eq!(
+            parse_ip_neigh(output),
+            vec![
+                n("192.168.1.20", "3c:f0:83:9e:6a:2c"),
+                n("192.168.1.1", "00:11:22:33:44:55"),
+                n("fe80::3ef0:83ff:fe9e:6a2c", "3c:f0:83:9e:6a:2c"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! Crate-wide error type.
+//!
+//! Every failure between "a request arrived" and "the TV answered" ends up as an
+//! [`LgtvError`]. Callers match on the variant to decide what to do (reconnect, back off,
+//! give up) and show its `Display` text to the user, so each message says what to check.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum LgtvError {
+    /// The TV could not be located on the network.
+    Discovery(String),
+    /// Nothing answered at the TV's address.
+    Unreachable(String),
+    /// The TV accepted the connection but was too slow.
+    Timeout { action: &'static str, after: Duration },
+    /// The socket closed before the TV answered.
+    Closed,
+    /// Registration failed, e.g. the prompt on the TV was declined.
+    Pairing(String),
+    /// The TV answered and refused the request.
+    Rejected(String),
+    /// The TV answered with something we don't understand.
+    Protocol(String),
+    /// The device at the TV's address is not the configured TV.
+    WrongDevice(String),
+    /// The active audio output could not be determined.
+    Probe(String),
+    /// The daemon could not listen for requests.
+    Listen { path: PathBuf, reason: String },
+}
+
+impl LgtvError {
+    /// Whether the connection should be dropped and re-established after this error.
+    pub fn breaks_connection(&self) -> bool {
+        matches!(self, LgtvError::Timeout { .. } | LgtvError::Closed)
+    }
+}
+
+impl fmt::Display for LgtvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LgtvError::Discovery(reason) => {
+                write!(f, "cannot find the TV: {}; check that it is on, on this network, and that `mac` is right", reason)
+            }
+            LgtvError::Unreachable(reason) => write!(f, "TV unreachable: {}; is it turned on?", reason),
+            LgtvError::Timeout { action, after } => write!(f, "TV did not {} within {:?}", action, after),
+            LgtvError::Closed => write!(f, "connection to the TV closed before it answered"),
+            LgtvError::Pairing(reason) => {
+                write!(f, "pairing failed: {}; accept the prompt on the TV, or delete ~/.lgtv_key to pair again", reason)
+            }
+            LgtvError::Rejected(reason) => write!(f, "TV rejected the request: {}", reason),
+            LgtvError::Protocol(reason) => write!(f, "unexpected response from the TV: {}", reason),
+            LgtvError::WrongDevice(reason) => {
+                write!(f, "not the configured TV: {}; check `mac` and the [identity] settings", reason)
+            }
+            LgtvError::Probe(reason) => write!(f, "cannot tell the active audio output: {}", reason),
+            LgtvError::Listen { path, reason } => write!(f, "cannot listen on {}: {}", path.display(), reason),
+        }
+    }
+}
+
+impl std::error::Error for LgtvError {}
@@ -0,0 +1,88 @@
+//! Checking that a connection reached the configured TV.
+//!
+//! The TV's address comes from tables and searches that another device can end up in, such
+//! as a second LG TV or whatever took over an old DHCP lease. Before pairing, its UPnP UUID
+//! is read over SSDP, so the pairing key never goes to the wrong TV. After pairing, the TV is
+//! asked for its model and serial number (`ssap://system/getSystemInfo` and
+//! `com.webos.service.update/getCurrentSWInformation`). Only what `[identity]` configures is
+//! checked, and a value the TV doesn't report counts as a mismatch.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::config::IdentityConfig;
+use crate::connection::TvConnection;
+use crate::error::LgtvError;
+use crate::ssdp;
+
+const SYSTEM_INFO_URI: &str = "ssap://system/getSystemInfo";
+const SW_INFO_URI: &str = "ssap://com.webos.service.update/getCurrentSWInformation";
+
+/// What the TV reports about itself; each field may come from either request.
+#[derive(Debug, Default)]
+struct Reported {
+    models: Vec<String>,
+    serial: Option<String>,
+}
+
+impl Reported {
+    fn read(&mut self, payload: &Value) {
+        let text = |key: &str| payload.get(key).and_then(Value::as_str).map(str::to_string);
+        self.models.extend(text("modelName").into_iter().chain(text("model_name")));
+        self.serial = self.serial.take().or(text("serialNumber")).or(text("serial_number"));
+    }
+}
+
+fn same(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+/// Fails with [`LgtvError::WrongDevice`] unless the device at `ip` answers SSDP with the
+/// configured UUID. Needs no pairing, so it runs before the stored key is sent. No answer
+/// within `wait` may just be a lost datagram, so that is a [`LgtvError::Discovery`] error,
+/// retried like any other.
+pub async fn verify_uuid(ip: &str, expected: &IdentityConfig, wait: Duration) -> Result<(), LgtvError> {
+    let Some(uuid) = &expected.uuid else { return Ok(()) };
+    let addr: IpAddr = ip.parse().map_err(|_| LgtvError::WrongDevice(format!("{} is not an IP address", ip)))?;
+    let Some(answer) = ssdp::query(SocketAddr::new(addr, 1900), wait).await? else {
+        return Err(LgtvError::Discovery(format!("{} did not answer SSDP within {:?}, cannot check its UUID", ip, wait)));
+    };
+    let found = answer.uuid;
+    if !found.as_deref().is_some_and(|u| same(u.trim_start_matches("uuid:"), uuid.trim_start_matches("uuid:"))) {
+        let found = found.as_deref().unwrap_or("no UUID over SSDP");
+        return Err(LgtvError::WrongDevice(format!("expected UUID {}, {} reports {}", uuid, ip, found)));
+    }
+    Ok(())
+}
+
+/// Fails with [`LgtvError::WrongDevice`] unless the paired TV at `ip` reports the configured
+/// model and serial number.
+pub async fn verify(tv: &TvConnection, ip: &str, expected: &IdentityConfig) -> Result<(), LgtvError> {
+    if expected.model.is_none() && expected.serial.is_none() {
+        return Ok(());
+    }
+    let mut reported = Reported::default();
+    for uri in [SYSTEM_INFO_URI, SW_INFO_URI] {
+        match tv.request(uri).await {
+            Ok(payload) => reported.read(&payload),
+            // Older firmware lacks one or the other.
+            Err(LgtvError::Rejected(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(model) = &expected.model
+        && !reported.models.iter().any(|m| same(m, model))
+    {
+        let found = if reported.models.is_empty() { "nothing".to_string() } else { reported.models.join(" / ") };
+        return Err(LgtvError::WrongDevice(format!("expected model {}, {} reports {}", model, ip, found)));
+    }
+    if let Some(serial) = &expected.serial
+        && !reported.serial.as_deref().is_some_and(|s| same(s, serial))
+    {
+        let found = reported.serial.as_deref().unwrap_or("no serial number");
+        return Err(LgtvError::WrongDevice(format!("expected serial {}, {} reports {}", serial, ip, found)));
+    }
+    Ok(())
+}
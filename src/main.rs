@@ -1,214 +1,250 @@
-#![allow(unused_imports)]
-use std::path::Path;
+//! LG TV Controller with Passthrough
+//!
+//! Architecture:
+//! 1. Fast Path: Checks the active audio output through a pluggable probe (`probe.rs`); on macOS
+//!    a native Swift helper (`get_audio_device`) answers in <10ms.
+//! 2. Passthrough: Karabiner sends commands EVERY keypress. This app ignores them if the TV
+//!    isn't the active audio device, allowing macOS to handle the volume natively.
+//! 3. Resilient: A connection state machine (`session.rs`), running on its own task so requests
+//!    never wait on it, reconnects with backoff when the TV is turned off or the network drops,
+//!    and health-checks the live connection.
+
+mod cli;
+mod command;
+mod config;
+mod connection;
+mod discovery;
+mod error;
+mod identity;
+mod mdns;
+mod probe;
+mod protocol;
+mod retry;
+mod server;
+mod session;
+mod ssdp;
+mod sweep;
+mod volume;
+
+use std::collections::VecDeque;
 use std::time::Duration;
-use std::process::Command;
-use nix::sys::stat::Mode;
-use nix::unistd::mkfifo;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc;
-use tokio::time::sleep;
-use lg_webos_client::client::{WebosClient, WebOsClientConfig};
-use lg_webos_client::command::Command as WebOsCommand;
-use home::home_dir;
-use regex::Regex;
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
-use tokio_tungstenite::tungstenite::Message;
-use tokio::net::TcpStream;
-use futures_util::stream::SplitSink;
-
-/// LG TV Controller with Passthrough
-/// 
-/// Architecture:
-/// 1. Fast Path: Uses a native Swift helper (`get_audio_device`) to check the active output in <10ms.
-/// 2. Passthrough: Karabiner sends commands EVERY keypress. This app ignores them if the TV
-///    isn't the active audio device, allowing macOS to handle the volume natively.
-/// 3. Resilient: Automatically reconnects if the TV is turned off or the network drops.
-
-const PIPE_PATH: &str = "/tmp/lgtv-pipe";
-
-// The name reported by CoreAudio for the LG TV when used as an audio sink.
-const TARGET_AUDIO_DEVICE: &str = "LG Monitor";
-
-// The MAC address of your LG TV.
-const TARGET_MAC: &str = "3C:F0:83:9E:6A:2C";
-
-type ClientType = WebosClient<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>;
-
-fn map_client_error<E: std::fmt::Debug>(e: E) -> Box<dyn std::error::Error> {
-    format!("{:?}", e).into()
-}
-
-/// Resolves the IP address of the LG TV using its MAC address.
-fn resolve_ip_from_mac() -> Option<String> {
-    let output = Command::new("arp").arg("-an").output().ok()?;
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let normalized_target = TARGET_MAC.to_lowercase();
-    let re = Regex::new(r"\(([^)]+)\) at ([0-9a-f:]+)").ok()?;
-
-    for line in output_str.lines() {
-        if let Some(caps) = re.captures(line) {
-            let ip = caps.get(1)?.as_str().to_string();
-            let mac = caps.get(2)?.as_str().to_string();
-            if mac.to_lowercase() == normalized_target {
-                return Some(ip);
+use config::{Config, Overrides};
+use error::LgtvError;
+use protocol::{Reply, Response, parse_request, respond};
+use retry::{Job, RetryQueue, respond_all};
+use server::AppEvent;
+use volume::VolumeStepper;
+
+/// Drains volume step events that arrive within `window` of the first one, summing their
+/// deltas. Stops at the first other event, which is queued in `pending` to keep ordering.
+async fn coalesce_volume(
+    rx: &mut mpsc::Receiver<AppEvent>,
+    pending: &mut VecDeque<AppEvent>,
+    stepper: &mut VolumeStepper,
+    window: Duration,
+    first_delta: i64,
+    first_reply: Reply,
+) -> (i64, Vec<Reply>) {
+    let mut total = first_delta;
+    let mut replies = vec![first_reply];
+    let deadline = tokio::time::Instant::now() + window;
+
+    while pending.is_empty() {
+        let Ok(Some(event)) = tokio::time::timeout_at(deadline, rx.recv()).await else { break };
+        let AppEvent::CommandReceived { line, reply } = event;
+        let step = parse_request(&line)
+            .ok()
+            .and_then(|req| Some((req.command.volume_step()?, req.id)));
+        match step {
+            Some(((direction, explicit), id)) => {
+                total += stepper.delta(direction, explicit);
+                replies.push((id, reply));
             }
+            None => pending.push_back(AppEvent::CommandReceived { line, reply }),
         }
     }
-    None
+    (total, replies)
 }
 
-/// Check if the target LG device is currently active in macOS.
-fn is_lg_tv_active_audio() -> bool {
-    let home = home_dir().expect("Cannot find home directory");
-    let swift_bin = home.join(".local/bin/get_audio_device");
-    let local_bin = std::env::current_dir().unwrap_or_default().join("get_audio_device");
-
-    let cmd_path = if swift_bin.exists() {
-        swift_bin
-    } else if local_bin.exists() {
-        local_bin
-    } else {
-        return false;
+#[tokio::main]
+async fn main() {
+    let (overrides, rest) = match Overrides::from_args(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("lgtv: {}\nRun `lgtv help` for usage.", e);
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    };
+    let invocation = match cli::parse(&rest) {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("lgtv: {}\nRun `lgtv help` for usage.", e);
+            std::process::exit(cli::EXIT_USAGE);
+        }
     };
 
-    if let Ok(out) = Command::new(cmd_path).output() {
-        let name = String::from_utf8_lossy(&out.stdout).trim().to_string();
-        return name.eq_ignore_ascii_case(TARGET_AUDIO_DEVICE);
+    match invocation {
+        cli::Invocation::Daemon => {}
+        cli::Invocation::Help => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        cli::Invocation::Discover => {
+            let path = overrides.config_file();
+            let config = Config::resolve(overrides).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(cli::EXIT_USAGE);
+            });
+            std::process::exit(cli::run_discover(&config, &path).await);
+        }
+        cli::Invocation::Client { command, output, direct: None } => {
+            let config = Config::resolve(overrides).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(cli::EXIT_USAGE);
+            });
+            std::process::exit(cli::run(&config.socket_path, &command, output).await);
+        }
+        cli::Invocation::Client { command, output, direct: Some(timeout) } => {
+            let config = Config::load_direct(overrides).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(cli::EXIT_USAGE);
+            });
+            let code = cli::run_direct(&config, &command, output, timeout).await;
+            std::process::exit(code);
+        }
     }
-    false
-}
 
-enum AppEvent {
-    CommandReceived(String),
+    let config = match Config::load(overrides) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    };
+    if let Err(e) = run_daemon(config).await {
+        eprintln!("lgtv: {}", e);
+        std::process::exit(cli::EXIT_FAILED);
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn run_daemon(config: Config) -> Result<(), LgtvError> {
     println!("Starting LG TV Controller...");
 
-    use std::os::unix::fs::PermissionsExt;
+    let (tx, mut rx) = mpsc::channel(32);
 
-    // 1. Setup Named Pipe
-    if !Path::new(PIPE_PATH).exists() {
-        mkfifo(PIPE_PATH, Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO).ok();
-        if let Ok(metadata) = std::fs::metadata(PIPE_PATH) {
-            let mut perms = metadata.permissions();
-            perms.set_mode(0o666);
-            let _ = std::fs::set_permissions(PIPE_PATH, perms);
-        }
+    // 1. Control Socket: every request gets a response line.
+    let listener = server::bind_socket(&config.socket_path)
+        .await
+        .map_err(|e| LgtvError::Listen { path: config.socket_path.clone(), reason: e.to_string() })?;
+    server::spawn_socket_server(listener, tx.clone());
+
+    // 2. Legacy Named Pipe: fire-and-forget lines from Karabiner.
+    if config.pipe_enabled {
+        server::setup_pipe(&config.pipe_path);
+        server::spawn_pipe_listener(config.pipe_path.clone(), tx.clone());
     }
 
-    // 2. Spawn Command Listener
-    let (tx, mut rx) = mpsc::channel(32);
-    let tx_clone = tx.clone();
-    
-    tokio::spawn(async move {
-        loop {
-            // Open RDWR to prevent EOF loops when no writers are present.
-            match OpenOptions::new().read(true).write(true).open(PIPE_PATH).await {
-                Ok(file) => {
-                    let mut reader = BufReader::new(file);
-                    let mut line = String::new();
-                    while let Ok(n) = reader.read_line(&mut line).await {
-                        if n == 0 { break; } 
-                        let cmd = line.trim().to_string();
-                        if !cmd.is_empty() {
-                             let _ = tx_clone.send(AppEvent::CommandReceived(cmd)).await;
-                        }
-                        line.clear();
-                    }
-                }
-                Err(_) => { sleep(Duration::from_secs(1)).await; }
-            }
-        }
-    });
+    let probe = probe::from_config(&config.audio);
+    let mut stepper = VolumeStepper::new(&config.volume);
+
+    // 3. Main Loop: Runs TV commands and replays parked ones; the session task keeps the connection.
+    let mut session = session::spawn(
+        config.normalized_mac(),
+        config.connection.clone(),
+        config.discovery.clone(),
+        config.identity.clone(),
+    );
+    let mut pending: VecDeque<AppEvent> = VecDeque::new();
+    let mut retries = RetryQueue::new(
+        config.connection.replay_queue,
+        Duration::from_millis(config.connection.replay_deadline_ms),
+    );
 
-    // 3. Main Loop: Handles connection state and TV commands.
-    let mut client: Option<ClientType> = None;
-    
     loop {
-        // A) Connection/Re-connection handling
-        if client.is_none() {
-             if let Some(ip) = resolve_ip_from_mac() {
-                let key_path = home_dir().expect("Cannot find home").join(".lgtv_key");
-                let mut key = if key_path.exists() {
-                    tokio::fs::read_to_string(&key_path).await.ok().map(|k| k.trim().to_string())
-                } else { None };
-
-                // Extract key from JSON if needed
-                if let Some(ref k) = key {
-                    if let Ok(v) = serde_json::from_str::<serde_json::Value>(k) {
-                        if let Some(ck) = v.get("client-key").and_then(|v| v.as_str()) {
-                            key = Some(ck.to_string());
-                        }
+        // Leftovers from volume coalescing first; otherwise whichever comes first, a request,
+        // a fresh connection to replay parked commands on, or a parked command expiring.
+        let event = match pending.pop_front() {
+            Some(event) => event,
+            None => tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                tv = session.reconnected() => {
+                    if let Err(e) = retries.replay(&tv, &config.volume).await {
+                        session.lost(&tv, format!("replayed command failed: {}", e));
                     }
+                    continue;
                 }
-
-                let url = format!("ws://{}:3000/", ip);
-                let config = WebOsClientConfig::new(&url, key.clone());
-                
-                if let Ok(c) = WebosClient::new(config).await {
-                    println!("Connected to TV at {}", ip);
-                    
-                    // Persist the pairing key if it's new or changed.
-                    if let Some(new_key) = &c.key {
-                        let new_key_trimmed = new_key.trim();
-                        if key.as_deref() != Some(new_key_trimmed) {
-                            let _ = tokio::fs::write(&key_path, new_key_trimmed.as_bytes()).await;
-                        }
-                    }
-                    client = Some(c);
+                _ = retries.next_expiry() => {
+                    retries.expire();
+                    continue;
                 }
-             }
-        }
-
-        // B) Process Incoming Commands
-        let wait_time = if client.is_none() { Duration::from_secs(5) } else { Duration::from_secs(3600) };
-        
-        match tokio::time::timeout(wait_time, rx.recv()).await {
-            Ok(Some(AppEvent::CommandReceived(cmd))) => {
-                // Ignore commands if the TV isn't the active audio device.
-                if !is_lg_tv_active_audio() && (cmd.starts_with("volume") || cmd == "mute" || cmd == "unmute") {
-                     continue;
+            },
+        };
+        let AppEvent::CommandReceived { line, reply } = event;
+
+        let request = match parse_request(&line) {
+            Ok(req) => req,
+            Err(e) => {
+                match e.id() {
+                    Some(id) => eprintln!("Ignoring request {}: {}", id, e),
+                    None => eprintln!("Ignoring '{}': {}", line, e),
                 }
+                respond(reply, Response::failure(e.id().map(str::to_string), &e));
+                continue;
+            }
+        };
+        let id = request.id.clone();
+
+        // Volume keys forwarded by Karabiner through the pipe are meant for the TV only while
+        // it is the active audio device. Socket clients ask for the TV explicitly.
+        if reply.is_none() && request.command.is_audio() {
+            match probe.is_active(&config.audio_device) {
+                Ok(true) => {}
+                Ok(false) => {
+                    respond(reply, Response::failure(id, "TV is not the active audio output"));
+                    continue;
+                }
+                Err(e) => {
+                    respond(reply, Response::failure(id, e));
+                    continue;
+                }
+            }
+        }
 
-                if let Some(c) = &client {
-                    let result = async {
-                        match cmd.as_str() {
-                            "volume_up" => {
-                                let resp = c.send_command(WebOsCommand::GetVolume).await.map_err(map_client_error)?;
-                                if let Some(p) = resp.payload {
-                                   if let Some(vol) = p.get("volumeStatus").and_then(|s| s.get("volume")).and_then(|v| v.as_i64()) {
-                                       let nv = (vol + 1).max(0).min(100);
-                                       let _ = c.send_command(WebOsCommand::SetVolume(nv as i8)).await;
-                                   }
-                                }
-                                Ok::<(), Box<dyn std::error::Error>>(()) 
-                            },
-                             "volume_down" => {
-                                let resp = c.send_command(WebOsCommand::GetVolume).await.map_err(map_client_error)?;
-                                if let Some(p) = resp.payload {
-                                   if let Some(vol) = p.get("volumeStatus").and_then(|s| s.get("volume")).and_then(|v| v.as_i64()) {
-                                       let nv = (vol - 1).max(0).min(100);
-                                       let _ = c.send_command(WebOsCommand::SetVolume(nv as i8)).await;
-                                   }
-                                }
-                                Ok(()) 
-                            },
-                            "mute" => { let _ = c.send_command(WebOsCommand::SetMute(true)).await; Ok(()) },
-                            "unmute" => { let _ = c.send_command(WebOsCommand::SetMute(false)).await; Ok(()) },
-                            _ => Ok(())
-                        }
-                    }.await;
-
-                    if result.is_err() {
-                        client = None; 
-                    }
+        // Connecting happens on the session task; without a connection, fail right away.
+        let Some(c) = session.tv() else {
+            respond(reply, Response::failure(id, "not connected to TV"));
+            continue;
+        };
+
+        let (mut job, replies) = match request.command.volume_step() {
+            // Merge the volume steps that arrive within the window into one SetVolume.
+            Some((direction, explicit)) => {
+                let window = Duration::from_millis(config.volume.coalesce_ms);
+                let delta = stepper.delta(direction, explicit);
+                let (total, replies) =
+                    coalesce_volume(&mut rx, &mut pending, &mut stepper, window, delta, (id, reply)).await;
+                (Job::Volume(total), replies)
+            }
+            None => (Job::Command(request.command), vec![(id, reply)]),
+        };
+
+        let result = job.run(&c, &config.volume).await;
+        match &result {
+            // Only a dead connection is worth a reconnect; a refusal is final. A timed-out
+            // command may still have been applied, so only a closed socket leads to a replay.
+            Err(e) if e.breaks_connection() => {
+                let reason = format!("command failed: {}", e);
+                match (e, job.for_replay()) {
+                    (LgtvError::Closed, Some(job)) => retries.park(job, replies, &reason),
+                    _ => respond_all(replies, &result),
                 }
-            },
-            Ok(None) => break, 
-            Err(_) => { }
+                session.lost(&c, reason);
+            }
+            _ => respond_all(replies, &result),
         }
     }
     Ok(())
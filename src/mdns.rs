@@ -0,0 +1,283 @@
+//! mDNS browsing for TVs.
+//!
+//! webOS TVs with AirPlay or HomeKit announce those services over multicast DNS. A one-shot
+//! query from an ephemeral port gets unicast answers (RFC 6762 §6.7), so no port 5353 socket
+//! is needed. The AirPlay record also carries the TV's MAC address as `deviceid`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+use crate::discovery::normalize_mac;
+use crate::error::LgtvError;
+
+/// The mDNS multicast group.
+pub const MULTICAST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+/// Services a webOS TV may announce.
+const SERVICES: &[&str] = &["_airplay._tcp.local", "_hap._tcp.local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// A TV that announced itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsTv {
+    pub ip: String,
+    pub mac: Option<String>,
+    pub name: String,
+    pub model: Option<String>,
+}
+
+fn query() -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, SERVICES.len() as u8, 0, 0, 0, 0, 0, 0];
+    for service in SERVICES {
+        for label in service.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+    }
+    packet
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+/// Reads a possibly compressed name at `pos`. Returns its labels and the position after it.
+fn read_name(buf: &[u8], mut pos: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the number of compression pointers followed, so a loop can't hang us.
+    for _ in 0..64 {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels, end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            pos = (u16_at(buf, pos)? & 0x3fff) as usize;
+            continue;
+        }
+        labels.push(String::from_utf8_lossy(buf.get(pos + 1..pos + 1 + len)?).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+fn key(labels: &[String]) -> String {
+    labels.join(".").to_ascii_lowercase()
+}
+
+enum Record {
+    Ptr { name: String, target: Vec<String> },
+    Srv { name: String, host: String },
+    Txt { name: String, entries: Vec<String> },
+    A { name: String, ip: Ipv4Addr },
+}
+
+/// Parses every answer, authority and additional record the answers need.
+fn parse_records(buf: &[u8]) -> Option<Vec<Record>> {
+    let questions = u16_at(buf, 4)?;
+    let records = u16_at(buf, 6)? as usize + u16_at(buf, 8)? as usize + u16_at(buf, 10)? as usize;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(buf, pos)?.1 + 4;
+    }
+
+    let mut parsed = Vec::new();
+    for _ in 0..records {
+        let (labels, after) = read_name(buf, pos)?;
+        let kind = u16_at(buf, after)?;
+        let len = u16_at(buf, after + 8)? as usize;
+        let data = after + 10;
+        let rdata = buf.get(data..data + len)?;
+        let name = key(&labels);
+        match kind {
+            TYPE_PTR => parsed.push(Record::Ptr { name, target: read_name(buf, data)?.0 }),
+            TYPE_SRV => parsed.push(Record::Srv { name, host: key(&read_name(buf, data + 6)?.0) }),
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                let mut i = 0;
+                while let Some(&n) = rdata.get(i) {
+                    let entry = rdata.get(i + 1..i + 1 + n as usize)?;
+                    entries.push(String::from_utf8_lossy(entry).into_owned());
+                    i += 1 + n as usize;
+                }
+                parsed.push(Record::Txt { name, entries });
+            }
+            TYPE_A if len == 4 => {
+                parsed.push(Record::A { name, ip: Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]) })
+            }
+            _ => {}
+        }
+        pos = data + len;
+    }
+    Some(parsed)
+}
+
+/// The TVs announced in one response from `from`. Other devices are skipped: a TV says it
+/// is made by LG, or has LG or webOS in its name.
+fn parse_response(buf: &[u8], from: IpAddr) -> Vec<MdnsTv> {
+    let Some(records) = parse_records(buf) else { return Vec::new() };
+    let services: Vec<String> = SERVICES.iter().map(|s| s.to_ascii_lowercase()).collect();
+
+    let mut txt: HashMap<&str, HashMap<String, String>> = HashMap::new();
+    let mut srv: HashMap<&str, &str> = HashMap::new();
+    let mut a: HashMap<&str, Ipv4Addr> = HashMap::new();
+    for record in &records {
+        match record {
+            Record::Txt { name, entries } => {
+                let map = entries
+                    .iter()
+                    .filter_map(|e| e.split_once('='))
+                    .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string()))
+                    .collect();
+                txt.insert(name, map);
+            }
+            Record::Srv { name, host } => {
+                srv.insert(name, host);
+            }
+            Record::A { name, ip } => {
+                a.insert(name, *ip);
+            }
+            Record::Ptr { .. } => {}
+        }
+    }
+
+    let mut tvs = Vec::new();
+    for record in &records {
+        let Record::Ptr { name, target } = record else { continue };
+        if !services.contains(name) {
+            continue;
+        }
+        let instance = key(target);
+        let label = target.first().cloned().unwrap_or_default();
+        let info = txt.get(instance.as_str());
+        let field = |k: &str| info.and_then(|m| m.get(k)).filter(|v| !v.is_empty()).cloned();
+
+        let maker = field("manufacturer").unwrap_or_default().to_ascii_lowercase();
+        let lower = label.to_ascii_lowercase();
+        if !(maker.starts_with("lg") || lower.contains("lg") || lower.contains("webos")) {
+            continue;
+        }
+        let ip = srv
+            .get(instance.as_str())
+            .and_then(|host| a.get(host))
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| from.to_string());
+        let tv = MdnsTv {
+            ip,
+            // AirPlay's `deviceid` is the MAC address.
+            mac: field("deviceid").and_then(|id| normalize_mac(&id)),
+            name: label,
+            model: field("model").or_else(|| field("md")),
+        };
+        if !tvs.contains(&tv) {
+            tvs.push(tv);
+        }
+    }
+    tvs
+}
+
+/// Asks for the TV services and collects the TVs that answer within `wait`, one entry per
+/// IP address.
+pub async fn browse(wait: Duration) -> Result<Vec<MdnsTv>, LgtvError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| LgtvError::Discovery(format!("mDNS: {}", e)))?;
+    socket
+        .send_to(&query(), MULTICAST)
+        .await
+        .map_err(|e| LgtvError::Discovery(format!("mDNS: cannot send to {}: {}", MULTICAST, e)))?;
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<MdnsTv> = Vec::new();
+    let mut buf = [0u8; 9000];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Ok((len, from)) = received else { continue };
+        for tv in parse_response(&buf[..len], from.ip()) {
+            match found.iter_mut().find(|seen| seen.ip == tv.ip) {
+                Some(seen) => {
+                    seen.mac = seen.mac.take().or(tv.mac);
+                    seen.model = seen.model.take().or(tv.model);
+                }
+                None => found.push(tv),
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An AirPlay answer as an LG TV sends it: the PTR record, with its TXT, SRV and A records
+    /// as additionals, names compressed against each other.
+    const AIRPLAY: &[u8] = b"\
+        \x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x03\x08_airplay\x04_tcp\x05local\x00\x00\x0c\
+        \x00\x01\x00\x00\x11\x94\x00\x1c\x19[LG] webOS TV OLED55C9PLA\xc0\x0c\xc0+\x00\x10\x80\x01\
+        \x00\x00\x11\x94\x00\x8b\x05acl=0\x1adeviceid=3C:F0:83:9E:6A:2C\x1bfeatures=0x7F8AD0,0x38BCB\
+        46\x1bmanufacturer=LG Electronics\x11model=OLED55C9PLA\x0dprotovers=1.1\x11srcvers=377.40.00\
+        \xc0+\x00!\x80\x01\x00\x00\x00x\x00\x12\x00\x00\x00\x00\x1bX\x09LGwebOSTV\xc0\x1a\xc0\xf0\
+        \x00\x01\x80\x01\x00\x00\x00x\x00\x04\xc0\xa8\x01\x14";
+
+    fn from() -> IpAddr {
+        "192.168.1.99".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_airplay_response() {
+        assert_eq!(
+            parse_response(AIRPLAY, from()),
+            [MdnsTv {
+                ip: "192.168.1.20".into(),
+                mac: Some("3c:f0:83:9e:6a:2c".into()),
+                name: "[LG] webOS TV OLED55C9PLA".into(),
+                model: Some("OLED55C9PLA".into()),
+            }]
+        );
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        // The SRV target is `LGwebOSTV` plus a pointer to `local` inside the question name.
+        let srv = AIRPLAY.windows(10).position(|w| w == b"\x09LGwebOSTV").unwrap();
+        assert_eq!(read_name(AIRPLAY, srv), Some((vec!["LGwebOSTV".to_string(), "local".to_string()], srv + 12)));
+    }
+
+    #[test]
+    fn stops_at_compression_loop() {
+        let mut looped = AIRPLAY.to_vec();
+        // Point the question name at itself.
+        looped[12..14].copy_from_slice(&[0xc0, 12]);
+        assert_eq!(read_name(&looped, 12), None);
+        assert_eq!(parse_response(&looped, from()), []);
+
+        // Two names pointing at each other.
+        let pair = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xc0, 14, 0xc0, 12];
+        assert_eq!(read_name(&pair, 12), None);
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        for len in 0..AIRPLAY.len() {
+            assert!(parse_records(&AIRPLAY[..len]).is_none(), "parsed {} of {} bytes", len, AIRPLAY.len());
+            assert_eq!(parse_response(&AIRPLAY[..len], from()), []);
+        }
+        // A TXT entry running past its record.
+        let mut txt = AIRPLAY.to_vec();
+        let acl = txt.windows(6).position(|w| w == b"\x05acl=0").unwrap();
+        txt[acl] = 0xff;
+        assert!(parse_records(&txt).is_none());
+    }
+}
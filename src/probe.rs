@@ -0,0 +1,449 @@
+//! Audio output probes: "is the TV the active sound output right now?"
+//!
+//! Volume and mute requests are only forwarded to the TV while it is the active output, so
+//! the OS keeps handling volume for speakers and headphones. Each backend reports the current
+//! default output; the configured `audio_device` is matched against its name or description.
+//!
+//! Backends spawn a process per query, so the daemon wraps them in a [`CachedProbe`], or
+//! in a [`WatchProbe`] fed by a long-running helper that prints the output name on changes.
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use home::home_dir;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::config::{AudioBackend, AudioConfig};
+use crate::error::LgtvError;
+
+/// The current default audio output as reported by a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioOutput {
+    /// Machine name, e.g. `alsa_output.pci-0000_01_00.1.hdmi-stereo`.
+    pub name: String,
+    /// Human-readable name, e.g. `LG TV SSCR2`, when the backend has one.
+    pub description: Option<String>,
+}
+
+impl AudioOutput {
+    fn named(name: impl Into<String>) -> Self {
+        AudioOutput { name: name.into(), description: None }
+    }
+
+    /// Case-insensitive match against the name or the description.
+    pub fn matches(&self, target: &str) -> bool {
+        self.name.eq_ignore_ascii_case(target)
+            || self.description.as_deref().is_some_and(|d| d.eq_ignore_ascii_case(target))
+    }
+}
+
+pub trait AudioOutputProbe: Send + Sync {
+    /// The current default output, `None` if there is none (nothing playing, no default
+    /// sink), or an error if the backend itself failed.
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError>;
+
+    /// Whether `target` is the active output. Backends that gate nothing override this.
+    fn is_active(&self, target: &str) -> Result<bool, LgtvError> {
+        Ok(self.active_output()?.is_some_and(|o| o.matches(target)))
+    }
+}
+
+/// Builds the probe selected in the config, with caching or a watcher in front of it.
+/// Must be called from within the Tokio runtime when `watch_command` is set.
+pub fn from_config(config: &AudioConfig) -> Box<dyn AudioOutputProbe> {
+    let backend = backend(config);
+    if config.backend == AudioBackend::Always {
+        return backend;
+    }
+    let cached: Box<dyn AudioOutputProbe> = if config.cache_ttl_ms > 0 {
+        Box::new(CachedProbe::new(backend, Duration::from_millis(config.cache_ttl_ms)))
+    } else {
+        backend
+    };
+    if config.watch_command.is_empty() {
+        cached
+    } else {
+        Box::new(WatchProbe::spawn(config.watch_command.clone(), cached))
+    }
+}
+
+fn backend(config: &AudioConfig) -> Box<dyn AudioOutputProbe> {
+    match config.backend {
+        AudioBackend::Swift => Box::new(SwiftHelperProbe::locate()),
+        AudioBackend::Pulseaudio => Box::new(PulseAudioProbe),
+        AudioBackend::Pipewire => Box::new(PipeWireProbe),
+        AudioBackend::Alsa => Box::new(AlsaProbe { root: PathBuf::from("/proc/asound") }),
+        AudioBackend::Command => Box::new(CommandProbe { argv: config.command.clone() }),
+        AudioBackend::Always => Box::new(AlwaysActive),
+    }
+}
+
+fn run(program: impl AsRef<std::ffi::OsStr>, args: &[&str]) -> Result<String, LgtvError> {
+    let name = program.as_ref().to_string_lossy().into_owned();
+    let out = Command::new(&program)
+        .args(args)
+        .output()
+        .map_err(|e| LgtvError::Probe(format!("cannot run {}: {}", name, e)))?;
+    if !out.status.success() {
+        return Err(LgtvError::Probe(format!("{} failed ({})", name, out.status)));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// macOS: the bundled `get_audio_device` Swift helper, which prints the CoreAudio device name.
+pub struct SwiftHelperProbe {
+    path: Option<PathBuf>,
+}
+
+impl SwiftHelperProbe {
+    /// Looks for the helper in `~/.local/bin`, then the current directory.
+    pub fn locate() -> Self {
+        let home = home_dir().expect("Cannot find home directory");
+        let swift_bin = home.join(".local/bin/get_audio_device");
+        let local_bin = std::env::current_dir().unwrap_or_default().join("get_audio_device");
+
+        let path = [swift_bin, local_bin].into_iter().find(|p| p.exists());
+        if path.is_none() {
+            eprintln!("get_audio_device helper not found; volume commands will pass through to macOS");
+        }
+        SwiftHelperProbe { path }
+    }
+}
+
+impl AudioOutputProbe for SwiftHelperProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            LgtvError::Probe("get_audio_device helper not found; install it to ~/.local/bin".to_string())
+        })?;
+        let name = run(path, &[])?.trim().to_string();
+        Ok((!name.is_empty()).then(|| AudioOutput::named(name)))
+    }
+}
+
+/// PulseAudio, or PipeWire through `pipewire-pulse`: `pactl get-default-sink`, with the
+/// description looked up in `pactl list sinks`.
+pub struct PulseAudioProbe;
+
+impl AudioOutputProbe for PulseAudioProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let name = run("pactl", &["get-default-sink"])?.trim().to_string();
+        if name.is_empty() {
+            return Ok(None);
+        }
+        let description = run("pactl", &["list", "sinks"]).ok().and_then(|list| sink_description(&list, &name));
+        Ok(Some(AudioOutput { name, description }))
+    }
+}
+
+/// Finds `Description:` of the sink block whose `Name:` is `sink` in `pactl list sinks` output.
+fn sink_description(list: &str, sink: &str) -> Option<String> {
+    let mut in_sink = false;
+    for line in list.lines() {
+        let line = line.trim();
+        if line.starts_with("Sink #") {
+            in_sink = false;
+        } else if let Some(name) = line.strip_prefix("Name:") {
+            in_sink = name.trim() == sink;
+        } else if in_sink && let Some(desc) = line.strip_prefix("Description:") {
+            return Some(desc.trim().to_string());
+        }
+    }
+    None
+}
+
+/// Native PipeWire: `wpctl inspect @DEFAULT_AUDIO_SINK@`.
+pub struct PipeWireProbe;
+
+impl AudioOutputProbe for PipeWireProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let out = run("wpctl", &["inspect", "@DEFAULT_AUDIO_SINK@"])?;
+        let name = wpctl_property(&out, "node.name")
+            .ok_or_else(|| LgtvError::Probe("wpctl inspect printed no node.name".to_string()))?;
+        Ok(Some(AudioOutput { name, description: wpctl_property(&out, "node.description") }))
+    }
+}
+
+/// Reads `key = "value"` from `wpctl inspect` output (lines may be prefixed with `*`).
+fn wpctl_property(output: &str, key: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (k, v) = line.trim().trim_start_matches('*').split_once('=')?;
+        (k.trim() == key).then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Plain ALSA has no default sink, so this reports the first card with a playback stream
+/// in the `RUNNING` state, by card ID (e.g. `HDMI`) with the PCM name as description.
+pub struct AlsaProbe {
+    root: PathBuf,
+}
+
+impl AudioOutputProbe for AlsaProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let mut cards: Vec<PathBuf> = std::fs::read_dir(&self.root)
+            .map_err(|e| LgtvError::Probe(format!("cannot read {}: {}", self.root.display(), e)))?
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("card")))
+            .collect();
+        cards.sort();
+
+        for card in cards {
+            let Ok(pcms) = std::fs::read_dir(&card) else { continue };
+            for pcm in pcms.flatten().map(|e| e.path()) {
+                let is_playback = pcm.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("pcm") && n.ends_with('p'));
+                if !is_playback || !alsa_pcm_running(&pcm) {
+                    continue;
+                }
+                let id = std::fs::read_to_string(card.join("id"))
+                    .map_err(|e| LgtvError::Probe(format!("cannot read the ID of {}: {}", card.display(), e)))?
+                    .trim()
+                    .to_string();
+                let description = std::fs::read_to_string(pcm.join("info")).ok().and_then(|info| {
+                    info.lines().find_map(|l| l.strip_prefix("name:").map(|n| n.trim().to_string()))
+                });
+                return Ok(Some(AudioOutput { name: id, description }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn alsa_pcm_running(pcm: &std::path::Path) -> bool {
+    let Ok(subs) = std::fs::read_dir(pcm) else { return false };
+    subs.flatten().any(|sub| {
+        std::fs::read_to_string(sub.path().join("status"))
+            .is_ok_and(|status| status.lines().any(|l| l.trim() == "state: RUNNING"))
+    })
+}
+
+/// Runs a user-supplied command and uses its trimmed stdout as the output name.
+pub struct CommandProbe {
+    argv: Vec<String>,
+}
+
+impl AudioOutputProbe for CommandProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let (program, args) = self
+            .argv
+            .split_first()
+            .ok_or_else(|| LgtvError::Probe("audio.command is empty".to_string()))?;
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let name = run(program, &args)?.trim().to_string();
+        Ok((!name.is_empty()).then(|| AudioOutput::named(name)))
+    }
+}
+
+/// Treats the TV as always active: every volume request goes to the TV.
+pub struct AlwaysActive;
+
+impl AudioOutputProbe for AlwaysActive {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        Ok(None)
+    }
+
+    fn is_active(&self, _target: &str) -> Result<bool, LgtvError> {
+        Ok(true)
+    }
+}
+
+type ProbeResult = Result<Option<AudioOutput>, LgtvError>;
+
+/// Remembers the inner probe's answer for `ttl`, so a held volume key doesn't fork a
+/// process per event.
+pub struct CachedProbe {
+    inner: Box<dyn AudioOutputProbe>,
+    ttl: Duration,
+    last: Mutex<Option<(Instant, ProbeResult)>>,
+}
+
+impl CachedProbe {
+    pub fn new(inner: Box<dyn AudioOutputProbe>, ttl: Duration) -> Self {
+        CachedProbe { inner, ttl, last: Mutex::new(None) }
+    }
+}
+
+impl AudioOutputProbe for CachedProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((at, output)) = last.as_ref()
+            && at.elapsed() < self.ttl
+        {
+            return output.clone();
+        }
+        let output = self.inner.active_output();
+        *last = Some((Instant::now(), output.clone()));
+        output
+    }
+}
+
+/// Tracks the output reported by a long-running helper that prints the current output name
+/// on startup and again on every change (an empty line meaning "unknown"). The helper is
+/// restarted if it exits; until it has reported, `fallback` answers.
+pub struct WatchProbe {
+    current: Arc<Mutex<Option<Option<AudioOutput>>>>,
+    fallback: Box<dyn AudioOutputProbe>,
+}
+
+impl WatchProbe {
+    pub fn spawn(argv: Vec<String>, fallback: Box<dyn AudioOutputProbe>) -> Self {
+        let current = Arc::new(Mutex::new(None));
+        let state = current.clone();
+
+        tokio::spawn(async move {
+            let (program, args) = argv.split_first().expect("watch command is not empty");
+            loop {
+                let child = tokio::process::Command::new(program)
+                    .args(args)
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn();
+                match child {
+                    Ok(mut child) => {
+                        let stdout = child.stdout.take().expect("stdout is piped");
+                        let mut lines = BufReader::new(stdout).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            let name = line.trim();
+                            let output = (!name.is_empty()).then(|| AudioOutput::named(name));
+                            *state.lock().unwrap() = Some(output);
+                        }
+                        let _ = child.wait().await;
+                        eprintln!("Audio watcher '{}' exited; restarting", program);
+                    }
+                    Err(e) => eprintln!("Cannot start audio watcher '{}': {}", program, e),
+                }
+                // Stale until the helper reports again.
+                *state.lock().unwrap() = None;
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+
+        WatchProbe { current, fallback }
+    }
+}
+
+impl AudioOutputProbe for WatchProbe {
+    fn active_output(&self) -> Result<Option<AudioOutput>, LgtvError> {
+        let current = self.current.lock().unwrap().clone();
+        match current {
+            Some(output) => Ok(output),
+            None => self.fallback.active_output(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `pactl list sinks` on PipeWire with pipewire-pulse, trimmed to two sinks.
+    const PACTL_SINKS: &str = "\
+Sink #47
+\tState: SUSPENDED
+\tName: alsa_output.pci-0000_00_1f.3.analog-stereo
+\tDescription: Built-in Audio Analog Stereo
+\tDriver: PipeWire
+\tSample Specification: s32le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: no
+\tVolume: front-left: 42597 /  65% / -11.23 dB,   front-right: 42597 /  65% / -11.23 dB
+\t        balance 0.00
+\tBase Volume: 65536 / 100% / 0.00 dB
+\tMonitor Source: alsa_output.pci-0000_00_1f.3.analog-stereo.monitor
+\tLatency: 0 usec, configured 0 usec
+\tFlags: HARDWARE HW_MUTE_CTRL HW_VOLUME_CTRL DECIBEL_VOLUME LATENCY
+\tProperties:
+\t\talsa.card = \"0\"
+\t\tdevice.description = \"Built-in Audio\"
+\t\tnode.name = \"alsa_output.pci-0000_00_1f.3.analog-stereo\"
+\tPorts:
+\t\tanalog-output-speaker: Speakers (type: Speaker, priority: 100, availability unknown)
+\tActive Port: analog-output-speaker
+\tFormats:
+\t\tpcm
+
+Sink #52
+\tState: RUNNING
+\tName: alsa_output.pci-0000_01_00.1.hdmi-stereo
+\tDescription: LG TV SSCR2
+\tDriver: PipeWire
+\tSample Specification: s32le 2ch 48000Hz
+\tChannel Map: front-left,front-right
+\tOwner Module: 4294967295
+\tMute: no
+\tVolume: front-left: 65536 / 100% / 0.00 dB,   front-right: 65536 / 100% / 0.00 dB
+\t        balance 0.00
+\tBase Volume: 65536 / 100% / 0.00 dB
+\tMonitor Source: alsa_output.pci-0000_01_00.1.hdmi-stereo.monitor
+\tLatency: 0 usec, configured 0 usec
+\tFlags: HARDWARE DECIBEL_VOLUME LATENCY
+\tProperties:
+\t\talsa.card = \"1\"
+\t\tdevice.description = \"GA102 High Definition Audio Controller\"
+\t\tnode.name = \"alsa_output.pci-0000_01_00.1.hdmi-stereo\"
+\tPorts:
+\t\thdmi-output-0: HDMI / DisplayPort (type: HDMI, priority: 5900, availability group: Legacy 1, available)
+\tActive Port: hdmi-output-0
+\tFormats:
+\t\tpcm
+";
+
+    /// `wpctl inspect @DEFAULT_AUDIO_SINK@`, trimmed.
+    const WPCTL_INSPECT: &str = "\
+id 52, type PipeWire:Interface:Node
+    alsa.card = \"1\"
+    alsa.card_name = \"HDA NVidia\"
+    api.alsa.path = \"hdmi:1\"
+  * client.id = \"35\"
+    device.api = \"alsa\"
+  * device.id = \"44\"
+  * factory.id = \"19\"
+    factory.name = \"api.alsa.pcm.sink\"
+  * media.class = \"Audio/Sink\"
+  * node.description = \"LG TV SSCR2\"
+    node.driver = \"true\"
+  * node.name = \"alsa_output.pci-0000_01_00.1.hdmi-stereo\"
+    node.nick = \"LG TV SSCR2\"
+  * object.id = \"52\"
+  * object.serial = \"53\"
+";
+
+    #[test]
+    fn finds_sink_description() {
+        let tv = "alsa_output.pci-0000_01_00.1.hdmi-stereo";
+        assert_eq!(sink_description(PACTL_SINKS, tv).as_deref(), Some("LG TV SSCR2"));
+        assert_eq!(
+            sink_description(PACTL_SINKS, "alsa_output.pci-0000_00_1f.3.analog-stereo").as_deref(),
+            Some("Built-in Audio Analog Stereo")
+        );
+        // Monitor sources and unknown sinks have no block of their own.
+        assert_eq!(sink_description(PACTL_SINKS, &format!("{}.monitor", tv)), None);
+        assert_eq!(sink_description(PACTL_SINKS, "bluez_output.00_11_22_33_44_55.1"), None);
+        assert_eq!(sink_description("", tv), None);
+    }
+
+    #[test]
+    fn reads_wpctl_properties() {
+        assert_eq!(
+            wpctl_property(WPCTL_INSPECT, "node.name").as_deref(),
+            Some("alsa_output.pci-0000_01_00.1.hdmi-stereo")
+        );
+        assert_eq!(wpctl_property(WPCTL_INSPECT, "node.description").as_deref(), Some("LG TV SSCR2"));
+        assert_eq!(wpctl_property(WPCTL_INSPECT, "alsa.card_name").as_deref(), Some("HDA NVidia"));
+        assert_eq!(wpctl_property(WPCTL_INSPECT, "node"), None);
+        assert_eq!(wpctl_property(WPCTL_INSPECT, "node.latency"), None);
+    }
+
+    #[test]
+    fn matches_name_or_description() {
+        let output = AudioOutput {
+            name: "alsa_output.pci-0000_01_00.1.hdmi-stereo".into(),
+            description: sink_description(PACTL_SINKS, "alsa_output.pci-0000_01_00.1.hdmi-stereo"),
+        };
+        assert!(output.matches("lg tv sscr2"));
+        assert!(output.matches("ALSA_OUTPUT.pci-0000_01_00.1.hdmi-stereo"));
+        assert!(!output.matches("LG TV"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Line protocol spoken on the pipe.
+//!
+//! Each line is either a plain-text command (`volume_up`, `input HDMI_2`), as sent by
+//! `karabiner.json`, or a JSON object carrying an optional request ID:
+//!
+//! ```text
+//! {"id":"42","cmd":"set_volume","value":30}
+//! {"cmd":"toast","value":"Dinner is ready"}
+//! {"cmd":"input","args":["HDMI_2"]}
+//! ```
+//!
+//! Clients connected to the control socket get one JSON [`Response`] line per request:
+//!
+//! ```text
+//! {"id":"42","ok":true,"payload":{"returnValue":true}}
+//! {"ok":false,"error":"unknown command 'volum'"}
+//! ```
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::command::{ParseError, TvCommand};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// Caller-chosen ID, echoed back so responses can be correlated.
+    pub id: Option<String>,
+    pub command: TvCommand,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The line looked like JSON but could not be decoded.
+    Json(String),
+    Command { id: Option<String>, error: ParseError },
+}
+
+impl RequestError {
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            RequestError::Json(_) => None,
+            RequestError::Command { id, .. } => id.as_deref(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Json(msg) => write!(f, "invalid JSON request: {}", msg),
+            RequestError::Command { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub ok: bool,
+    /// Raw payload returned by the TV, e.g. `volumeStatus` for `get_volume`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn success(id: Option<String>, payload: Option<Value>) -> Self {
+        Response { id, ok: true, payload, error: None }
+    }
+
+    pub fn failure(id: Option<String>, error: impl ToString) -> Self {
+        Response { id, ok: false, payload: None, error: Some(error.to_string()) }
+    }
+
+    /// Single-line JSON encoding, newline-terminated.
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("Response is always serialisable");
+        line.push('\n');
+        line
+    }
+}
+
+/// A request's ID and where to send its response; pipe requests have nowhere to send one.
+pub type Reply = (Option<String>, Option<oneshot::Sender<Response>>);
+
+/// Sends `response` back to the client, if it is still waiting.
+pub fn respond(reply: Option<oneshot::Sender<Response>>, response: Response) {
+    if let Some(reply) = reply {
+        let _ = reply.send(response);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRequest {
+    #[serde(default)]
+    id: Option<Value>,
+    cmd: String,
+    #[serde(default)]
+    value: Option<Value>,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+/// Parses one line, detecting JSON by its leading `{`.
+pub fn parse_request(line: &str) -> Result<Request, RequestError> {
+    let line = line.trim();
+    if !line.starts_with('{') {
+        let command = line.parse().map_err(|error| RequestError::Command { id: None, error })?;
+        return Ok(Request { id: None, command });
+    }
+
+    let req: JsonRequest = serde_json::from_str(line).map_err(|e| RequestError::Json(e.to_string()))?;
+    let id = req.id.map(|v| match v {
+        Value::String(s) => s,
+        other => other.to_string(),
+    });
+    let args: Vec<String> = req.value.into_iter().chain(req.args).map(arg_to_string).collect();
+
+    match TvCommand::from_parts(&req.cmd, &args) {
+        Ok(command) => Ok(Request { id, command }),
+        Err(error) => Err(RequestError::Command { id, error }),
+    }
+}
+
+fn arg_to_string(v: Value) -> String {
+    match v {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_text() {
+        let req = parse_request("  input hdmi2\n").unwrap();
+        assert_eq!(req, Request { id: None, command: TvCommand::Input("HDMI_2".into()) });
+
+        let err = parse_request("volume 200").unwrap_err();
+        assert!(matches!(err, RequestError::Command { id: None, error: ParseError::OutOfRange { .. } }));
+    }
+
+    #[test]
+    fn parses_json() {
+        let req = parse_request(r#"{"id":"42","cmd":"set_volume","value":30}"#).unwrap();
+        assert_eq!(req, Request { id: Some("42".into()), command: TvCommand::SetVolume(30) });
+
+        let req = parse_request(r#"  {"id":7,"cmd":"toast","value":"Dinner is ready"}"#).unwrap();
+        assert_eq!(req, Request { id: Some("7".into()), command: TvCommand::Toast("Dinner is ready".into()) });
+
+        let req = parse_request(r#"{"cmd":"input","args":["HDMI_2"]}"#).unwrap();
+        assert_eq!(req, Request { id: None, command: TvCommand::Input("HDMI_2".into()) });
+    }
+
+    #[test]
+    fn json_errors_keep_the_id() {
+        let err = parse_request(r#"{"id":"9","cmd":"volum"}"#).unwrap_err();
+        assert_eq!(err.id(), Some("9"));
+        assert!(matches!(err, RequestError::Command { error: ParseError::UnknownCommand(_), .. }));
+
+        assert!(matches!(parse_request(r#"{"cmd":"mute""#), Err(RequestError::Json(_))));
+        assert!(matches!(parse_request(r#"{"cmd":"mute","extra":1}"#), Err(RequestError::Json(_))));
+        // Only a leading brace means JSON.
+        assert!(matches!(parse_request(r#"toast {"a":1}"#), Ok(Request { command: TvCommand::Toast(_), .. })));
+    }
+}
@@ -0,0 +1,273 @@
+//! Replaying commands that failed because the connection dropped.
+//!
+//! The first attempt may have reached the TV before the connection dropped, so only commands
+//! that are safe to send twice are kept, and volume steps are kept as the absolute volume
+//! they were heading for. A failed command is parked with its pending replies. When the
+//! connection comes back it is sent once more; if that doesn't happen within the deadline it
+//! expires, so a volume key pressed half a minute ago never fires after the fact.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::time::{Instant, sleep_until};
+
+use crate::command::TvCommand;
+use crate::config::VolumeConfig;
+use crate::connection::{TvConnection, execute, set_volume, target_volume};
+use crate::error::LgtvError;
+use crate::protocol::{Reply, Response, respond};
+
+/// What the dispatcher sends to the TV for one or more requests.
+pub enum Job {
+    Command(TvCommand),
+    /// A burst of coalesced volume steps, as a net delta.
+    Volume(i64),
+    /// Volume steps turned into the volume they were heading for, for replay.
+    VolumeTo(u8),
+}
+
+impl Job {
+    /// Sends the job. Volume steps become the absolute volume they lead to before anything
+    /// is sent, so the job records what a replay has to set.
+    pub async fn run(&mut self, tv: &TvConnection, volume: &VolumeConfig) -> Result<Option<Value>, LgtvError> {
+        match self {
+            Job::Command(command) => execute(tv, command, volume).await,
+            Job::Volume(delta) => {
+                let Some(target) = target_volume(tv, *delta, volume.max_volume).await? else { return Ok(None) };
+                *self = Job::VolumeTo(target);
+                set_volume(tv, target).await
+            }
+            Job::VolumeTo(target) => set_volume(tv, *target).await,
+        }
+    }
+
+    /// The job to send again after reconnecting, if sending it twice is harmless. Volume
+    /// steps still held as a delta never reached the TV; once sent they are an absolute
+    /// volume, which is safe to set again.
+    pub fn for_replay(self) -> Option<Job> {
+        match self {
+            Job::Command(command) if !command.is_idempotent() => None,
+            job => Some(job),
+        }
+    }
+}
+
+/// Sends the same result to every request the job stood for.
+pub fn respond_all<E: fmt::Display>(replies: Vec<Reply>, result: &Result<Option<Value>, E>) {
+    for (id, reply) in replies {
+        let response = match result {
+            Ok(payload) => Response::success(id, payload.clone()),
+            Err(e) => Response::failure(id, e),
+        };
+        respond(reply, response);
+    }
+}
+
+struct Parked {
+    job: Job,
+    replies: Vec<Reply>,
+    expires: Instant,
+}
+
+/// Failed jobs waiting for a reconnect, oldest first.
+pub struct RetryQueue {
+    items: VecDeque<Parked>,
+    capacity: usize,
+    deadline: Duration,
+}
+
+impl RetryQueue {
+    pub fn new(capacity: usize, deadline: Duration) -> Self {
+        RetryQueue { items: VecDeque::new(), capacity, deadline }
+    }
+
+    /// Parks a job that failed with `error`. When replay is disabled, or to make room in a
+    /// full queue, the job (or the oldest one) is failed right away.
+    pub fn park(&mut self, job: Job, replies: Vec<Reply>, error: &str) {
+        if self.capacity == 0 || self.deadline.is_zero() {
+            return respond_all(replies, &Err(error));
+        }
+        if self.items.len() >= self.capacity
+            && let Some(oldest) = self.items.pop_front()
+        {
+            respond_all(oldest.replies, &Err("dropped from the full retry queue"));
+        }
+        self.items.push_back(Parked { job, replies, expires: Instant::now() + self.deadline });
+    }
+
+    /// Waits until the oldest job expires. Never resolves while the queue is empty.
+    pub async fn next_expiry(&self) {
+        match self.items.front() {
+            Some(parked) => sleep_until(parked.expires).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Fails every job whose deadline has passed.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some(parked) = self.items.front()
+            && parked.expires <= now
+        {
+            let parked = self.items.pop_front().expect("front exists");
+            respond_all(parked.replies, &Err("connection lost; command expired before reconnecting"));
+        }
+    }
+
+    /// Sends the jobs that are still live, once each, in order. Stops at the first error that
+    /// breaks the connection, after which the remaining jobs stay parked for the next one.
+    pub async fn replay(&mut self, tv: &TvConnection, volume: &VolumeConfig) -> Result<(), LgtvError> {
+        self.expire();
+        while let Some(mut parked) = self.items.pop_front() {
+            let result = parked.job.run(tv, volume).await;
+            respond_all(parked.replies, &result);
+            if let Err(e) = result
+                && e.breaks_connection()
+            {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::config::ConnectionConfig;
+    use crate::connection::pair;
+
+    const DEADLINE: Duration = Duration::from_secs(30);
+
+    fn reply() -> (Vec<Reply>, oneshot::Receiver<Response>) {
+        let (tx, rx) = oneshot::channel();
+        (vec![(None, Some(tx))], rx)
+    }
+
+    fn error(rx: &mut oneshot::Receiver<Response>) -> Option<String> {
+        rx.try_recv().expect("answered").error
+    }
+
+    /// A paired connection to a TV on localhost that answers every request, and closes the
+    /// connection when asked to launch `drop`.
+    async fn fake_tv() -> TvConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let msg: Value = serde_json::from_str(&text).unwrap();
+                let id = &msg["id"];
+                let answer = match msg["uri"].as_str() {
+                    _ if msg["type"] == "register" => json!({"type": "registered", "id": id, "payload": {"client-key": "key"}}),
+                    Some("ssap://system.launcher/launch") if msg["payload"]["id"] == "drop" => break,
+                    Some("ssap://audio/getVolume") => json!({
+                        "type": "response",
+                        "id": id,
+                        "payload": {"returnValue": true, "volumeStatus": {"volume": 10, "muteStatus": false}},
+                    }),
+                    _ => json!({"type": "response", "id": id, "payload": {"returnValue": true}}),
+                };
+                ws.send(Message::Text(answer.to_string())).await.unwrap();
+            }
+        });
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr)).await.unwrap();
+        pair(ws, &ConnectionConfig::default()).await.unwrap().0
+    }
+
+    #[test]
+    fn replays_only_idempotent_commands() {
+        let replayed = |job: Job| job.for_replay().is_some();
+        assert!(replayed(Job::Command(TvCommand::SetVolume(20))));
+        assert!(replayed(Job::Command(TvCommand::Mute)));
+        assert!(replayed(Job::Command(TvCommand::Input("HDMI_2".into()))));
+        assert!(replayed(Job::VolumeTo(20)));
+        // Never sent, so it can't have been applied.
+        assert!(replayed(Job::Volume(3)));
+        assert!(!replayed(Job::Command(TvCommand::VolumeUp(None))));
+        assert!(!replayed(Job::Command(TvCommand::MuteToggle)));
+        assert!(!replayed(Job::Command(TvCommand::PowerOff)));
+        assert!(!replayed(Job::Command(TvCommand::Toast("hi".into()))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queue_drops_oldest() {
+        let mut queue = RetryQueue::new(2, DEADLINE);
+        let (first, mut first_rx) = reply();
+        let (second, mut second_rx) = reply();
+        let (third, mut third_rx) = reply();
+        queue.park(Job::Command(TvCommand::Mute), first, "closed");
+        queue.park(Job::Command(TvCommand::Play), second, "closed");
+        queue.park(Job::Command(TvCommand::Pause), third, "closed");
+
+        assert_eq!(error(&mut first_rx).as_deref(), Some("dropped from the full retry queue"));
+        assert!(second_rx.try_recv().is_err() && third_rx.try_recv().is_err());
+        assert_eq!(queue.items.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disabled_queue_fails_right_away() {
+        for mut queue in [RetryQueue::new(0, DEADLINE), RetryQueue::new(8, Duration::ZERO)] {
+            let (replies, mut rx) = reply();
+            queue.park(Job::Command(TvCommand::Mute), replies, "connection closed");
+            assert_eq!(error(&mut rx).as_deref(), Some("connection closed"));
+            assert!(queue.items.is_empty());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_after_deadline() {
+        let mut queue = RetryQueue::new(8, DEADLINE);
+        let (first, mut first_rx) = reply();
+        queue.park(Job::Command(TvCommand::Mute), first, "closed");
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (second, mut second_rx) = reply();
+        queue.park(Job::Command(TvCommand::Play), second, "closed");
+
+        // The first job's deadline is what the queue waits for.
+        let started = Instant::now();
+        queue.next_expiry().await;
+        assert_eq!(started.elapsed(), Duration::from_secs(20));
+        queue.expire();
+        assert_eq!(
+            error(&mut first_rx).as_deref(),
+            Some("connection lost; command expired before reconnecting")
+        );
+        assert!(second_rx.try_recv().is_err());
+        assert_eq!(queue.items.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        queue.expire();
+        assert!(error(&mut second_rx).is_some());
+        assert!(queue.items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_stops_when_the_connection_breaks() {
+        let tv = fake_tv().await;
+        let mut queue = RetryQueue::new(8, DEADLINE);
+        let (first, mut first_rx) = reply();
+        let (second, mut second_rx) = reply();
+        let (third, mut third_rx) = reply();
+        queue.park(Job::Command(TvCommand::Mute), first, "closed");
+        queue.park(Job::Command(TvCommand::Launch("drop".into())), second, "closed");
+        queue.park(Job::Command(TvCommand::Unmute), third, "closed");
+
+        let result = queue.replay(&tv, &VolumeConfig::default()).await;
+        assert!(matches!(result, Err(LgtvError::Closed)), "{:?}", result.err());
+        assert_eq!(error(&mut first_rx), None);
+        assert!(error(&mut second_rx).is_some());
+        // Left for the next connection.
+        assert!(third_rx.try_recv().is_err());
+        assert_eq!(queue.items.len(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+//! Request listeners feeding the main loop.
+//!
+//! - Control socket: any number of concurrent clients, each with its own request/response
+//!   stream. Responses on a connection come back in request order.
+//! - Named pipe (legacy): fire-and-forget lines, as sent by `karabiner.json`.
+
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::sys::stat::Mode;
+use nix::unistd::mkfifo;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+
+use crate::protocol::Response;
+
+pub enum AppEvent {
+    /// A request line, plus where to send the response for clients that want one.
+    CommandReceived { line: String, reply: Option<oneshot::Sender<Response>> },
+}
+
+/// Binds the control socket, replacing a stale socket file left by a previous run.
+pub async fn bind_socket(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another lgtv daemon is already listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Accepts control socket clients, serving each on its own task.
+pub fn spawn_socket_server(listener: UnixListener, tx: mpsc::Sender<AppEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, tx.clone()));
+                }
+                Err(e) => {
+                    eprintln!("Control socket accept failed: {}", e);
+                    sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+}
+
+async fn handle_client(stream: UnixStream, tx: mpsc::Sender<AppEvent>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if tx.send(AppEvent::CommandReceived { line, reply: Some(reply_tx) }).await.is_err() {
+            return;
+        }
+        let response = reply_rx
+            .await
+            .unwrap_or_else(|_| Response::failure(None, "daemon dropped the request"));
+        if write.write_all(response.to_line().as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Creates the named pipe, world-writable so Karabiner's shell commands can reach it.
+pub fn setup_pipe(path: &Path) {
+    if !path.exists() {
+        mkfifo(path, Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO).ok();
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let mut perms = metadata.permissions();
+            perms.set_mode(0o666);
+            let _ = std::fs::set_permissions(path, perms);
+        }
+    }
+}
+
+/// Reads lines from the named pipe. Pipe requests get no response.
+pub fn spawn_pipe_listener(path: PathBuf, tx: mpsc::Sender<AppEvent>) {
+    tokio::spawn(async move {
+        loop {
+            // Open RDWR to prevent EOF loops when no writers are present.
+            match OpenOptions::new().read(true).write(true).open(&path).await {
+                Ok(file) => {
+                    let mut reader = BufReader::new(file);
+                    let mut line = String::new();
+                    while let Ok(n) = reader.read_line(&mut line).await {
+                        if n == 0 { break; }
+                        let cmd = line.trim().to_string();
+                        if !cmd.is_empty() {
+                            let _ = tx.send(AppEvent::CommandReceived { line: cmd, reply: None }).await;
+                        }
+                        line.clear();
+                    }
+                }
+                Err(_) => { sleep(Duration::from_secs(1)).await; }
+            }
+        }
+    });
+}
@@ -0,0 +1,270 @@
+//! Connection state machine.
+//!
+//! ```text
+//! Disconnected -> Resolving -> Connecting -> Pairing -> Connected
+//!       ^             |             |           |           |
+//!       +-------------+-------------+-----------+-----------+  (failure, socket closed, failed ping)
+//! ```
+//!
+//! A connection only counts as Connected once the TV has been verified to be the configured
+//! one (`identity.rs`). Failed attempts are retried with exponential backoff plus jitter.
+//! While connected the TV is pinged periodically, and a closed socket is noticed right away
+//! instead of on the next key press.
+//!
+//! The session runs on its own task, so resolving and connecting never hold up requests; the
+//! dispatcher sees the current connection, if any, through a [`SessionHandle`].
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lg_webos_client::command::Command as WebOsCommand;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{Instant, sleep_until};
+
+use crate::config::{ConnectionConfig, DiscoveryConfig, IdentityConfig};
+use crate::connection::{ConnectionState, TvConnection, connect_verified};
+use crate::error::LgtvError;
+use crate::volume::VolumeState;
+
+/// Exponential backoff with jitter: each delay is drawn from the upper half of
+/// `initial * 2^failures`, capped at `max`.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, failures: 0 }
+    }
+
+    /// Delay before the next attempt; every call counts as one more failure.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.initial.saturating_mul(1 << self.failures.min(16)).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+        exp.mul_f64(0.5 + jitter() / 2.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Skips straight to the longest delay.
+    pub fn max_out(&mut self) {
+        self.failures = u32::BITS;
+    }
+}
+
+/// A number in `[0, 1)`. Only used to spread out retries, so the clock hashed with std's
+/// randomly keyed hasher is random enough. The raw clock isn't: on macOS it only has
+/// microsecond resolution, so its low digits never change.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    (hasher.finish() % 1_000_000) as f64 / 1_000_000.0
+}
+
+fn log_transition(current: &mut ConnectionState, next: ConnectionState, reason: Option<&str>) {
+    match reason {
+        Some(reason) => println!("Connection: {} -> {} ({})", current, next, reason),
+        None => println!("Connection: {} -> {}", current, next),
+    }
+    *current = next;
+}
+
+/// What [`Session::next_wake`] woke up for.
+enum Wake {
+    /// Time for the next connection attempt.
+    Retry,
+    /// Time to ping the connected TV.
+    HealthCheck,
+    /// The socket closed.
+    Closed,
+}
+
+type Lost = (Arc<TvConnection>, String);
+
+/// The dispatcher's view of the session.
+pub struct SessionHandle {
+    current: watch::Receiver<Option<Arc<TvConnection>>>,
+    lost: mpsc::UnboundedSender<Lost>,
+}
+
+impl SessionHandle {
+    /// The connected TV, if any. Never waits.
+    pub fn tv(&self) -> Option<Arc<TvConnection>> {
+        self.current.borrow().clone()
+    }
+
+    /// Reports that a command on `tv` failed in a way that means the connection is gone.
+    /// Ignored if the session has already moved on to another connection.
+    pub fn lost(&self, tv: &Arc<TvConnection>, reason: String) {
+        let _ = self.lost.send((tv.clone(), reason));
+    }
+
+    /// Waits for the next successful connection.
+    pub async fn reconnected(&mut self) -> Arc<TvConnection> {
+        loop {
+            if self.current.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+            if let Some(tv) = self.current.borrow_and_update().clone() {
+                return tv;
+            }
+        }
+    }
+}
+
+/// Starts the session task for the TV with this MAC address.
+pub fn spawn(mac: String, config: ConnectionConfig, discovery: DiscoveryConfig, identity: IdentityConfig) -> SessionHandle {
+    let (published, current) = watch::channel(None);
+    let (lost, lost_rx) = mpsc::unbounded_channel();
+    let backoff = Backoff::new(
+        Duration::from_millis(config.retry_initial_ms),
+        Duration::from_millis(config.retry_max_ms),
+    );
+    let session = Session {
+        mac,
+        config,
+        discovery,
+        identity,
+        state: ConnectionState::Disconnected,
+        backoff,
+        tv: None,
+        published,
+        due: Instant::now(),
+    };
+    tokio::spawn(session.run(lost_rx));
+    SessionHandle { current, lost }
+}
+
+/// The connection to the TV and its state.
+struct Session {
+    mac: String,
+    config: ConnectionConfig,
+    discovery: DiscoveryConfig,
+    identity: IdentityConfig,
+    state: ConnectionState,
+    backoff: Backoff,
+    tv: Option<Arc<TvConnection>>,
+    /// Where the dispatcher picks up `tv`.
+    published: watch::Sender<Option<Arc<TvConnection>>>,
+    /// When the next connection attempt or health check is due.
+    due: Instant,
+}
+
+impl Session {
+    async fn run(mut self, mut lost: mpsc::UnboundedReceiver<Lost>) {
+        loop {
+            tokio::select! {
+                wake = self.next_wake() => self.handle(wake).await,
+                Some((tv, reason)) = lost.recv() => {
+                    if self.tv.as_ref().is_some_and(|current| Arc::ptr_eq(current, &tv)) {
+                        self.lost(&reason);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_tv(&mut self, tv: Option<Arc<TvConnection>>) {
+        self.tv = tv;
+        self.published.send_replace(self.tv.clone());
+    }
+
+    fn transition(&mut self, state: ConnectionState, reason: Option<&str>) {
+        log_transition(&mut self.state, state, reason);
+    }
+
+    /// Goes through Resolving, Connecting and Pairing. On failure, falls back to Disconnected
+    /// and schedules the next attempt.
+    async fn connect(&mut self) {
+        let state = &mut self.state;
+        let connected = connect_verified(&self.mac, &self.config, &self.discovery, &self.identity, |next, reason| {
+            log_transition(state, next, reason)
+        })
+        .await;
+        match connected {
+            Ok((tv, ip)) => {
+                self.transition(ConnectionState::Connected(ip), None);
+                self.set_tv(Some(Arc::new(tv)));
+                self.backoff.reset();
+                self.schedule_health_check();
+            }
+            Err(e) => {
+                // Don't keep putting pairing prompts on screen after one was declined, or
+                // keep pairing with a device that isn't the configured TV.
+                if let LgtvError::Pairing(_) | LgtvError::WrongDevice(_) = e {
+                    self.backoff.max_out();
+                }
+                self.fail(&e.to_string());
+            }
+        }
+    }
+
+    fn fail(&mut self, reason: &str) {
+        let delay = self.backoff.next_delay();
+        self.transition(ConnectionState::Disconnected, Some(&format!("{}; retrying in {:.1?}", reason, delay)));
+        self.set_tv(None);
+        self.due = Instant::now() + delay;
+    }
+
+    /// Drops the connection, e.g. after a failed command, and schedules a reconnect.
+    fn lost(&mut self, reason: &str) {
+        if self.tv.is_some() {
+            self.fail(reason);
+        }
+    }
+
+    fn schedule_health_check(&mut self) {
+        self.due = Instant::now() + Duration::from_millis(self.config.health_interval_ms);
+    }
+
+    /// Waits until the session needs attention: a reconnect or health check is due, or the
+    /// socket has closed. Never resolves while connected with health checks disabled and
+    /// the socket open.
+    async fn next_wake(&self) -> Wake {
+        let Some(tv) = &self.tv else {
+            sleep_until(self.due).await;
+            return Wake::Retry;
+        };
+        let health = async {
+            if self.config.health_interval_ms == 0 {
+                std::future::pending::<()>().await;
+            }
+            sleep_until(self.due).await;
+        };
+        tokio::select! {
+            _ = tv.closed() => Wake::Closed,
+            _ = health => Wake::HealthCheck,
+        }
+    }
+
+    async fn handle(&mut self, wake: Wake) {
+        match wake {
+            Wake::Retry => self.connect().await,
+            Wake::Closed if self.tv.as_ref().is_some_and(|tv| tv.timed_out()) => self.lost("the TV did not answer in time"),
+            Wake::Closed => self.lost("connection closed by the TV"),
+            Wake::HealthCheck => self.health_check().await,
+        }
+    }
+
+    /// Reads the volume as a ping, refreshing the volume model on the way. Any answer, even a
+    /// refusal, shows the connection is alive.
+    async fn health_check(&mut self) {
+        let Some(tv) = &self.tv else { return };
+        match tv.send(WebOsCommand::GetVolume).await {
+            Ok(resp) => {
+                if let Some(payload) = &resp.payload {
+                    tv.volume.lock().unwrap().merge(VolumeState::from_payload(payload));
+                }
+                self.schedule_health_check();
+            }
+            Err(e) if e.breaks_connection() => self.lost(&format!("health check failed: {}", e)),
+            Err(_) => self.schedule_health_check(),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! SSDP discovery of webOS TVs.
+//!
+//! An `M-SEARCH` for the webOS second-screen service goes out to the SSDP multicast group and
+//! every TV on the LAN answers from its own address. The answer names the TV's UUID and points
+//! at a UPnP device description, which has its friendly name and model.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::future::join_all;
+use regex::Regex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{Instant, timeout, timeout_at};
+
+use crate::error::LgtvError;
+
+/// The service every webOS TV advertises.
+pub const SEARCH_TARGET: &str = "urn:lge-com:service:webos-second-screen:1";
+
+/// The SSDP multicast group.
+pub const MULTICAST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+/// A TV that answered the search.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TvDescriptor {
+    pub ip: String,
+    pub name: Option<String>,
+    pub model: Option<String>,
+    /// Without the `uuid:` prefix.
+    pub uuid: Option<String>,
+    /// URL of the UPnP device description.
+    pub location: Option<String>,
+}
+
+fn m_search() -> String {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+        MULTICAST, SEARCH_TARGET
+    )
+}
+
+/// Parses one search response from `from`. Returns `None` for anything that isn't a webOS
+/// TV answering our search.
+pub fn parse_response(text: &str, from: IpAddr) -> Option<TvDescriptor> {
+    let mut lines = text.lines();
+    if !lines.next()?.trim().eq_ignore_ascii_case("HTTP/1.1 200 OK") {
+        return None;
+    }
+    let mut tv = TvDescriptor { ip: from.to_string(), ..Default::default() };
+    let mut st = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "st" => st = Some(value.to_string()),
+            "location" => tv.location = Some(value.to_string()),
+            // `uuid:<uuid>::urn:lge-com:service:webos-second-screen:1`
+            "usn" => tv.uuid = value.strip_prefix("uuid:").map(|v| v.split("::").next().unwrap_or(v).to_string()),
+            "dlnadevicename.lge.com" => tv.name = Some(percent_decode(value)),
+            _ => {}
+        }
+    }
+    (st.as_deref() == Some(SEARCH_TARGET)).then_some(tv)
+}
+
+/// Fills in the name, model and UUID from a UPnP device description, keeping what the
+/// search response already had when the description leaves them out.
+pub fn parse_description(xml: &str, tv: &mut TvDescriptor) {
+    let tag = |name: &str| {
+        let re = Regex::new(&format!(r"<{0}>\s*([^<]*?)\s*</{0}>", name)).expect("valid regex");
+        re.captures(xml).map(|c| xml_unescape(&c[1])).filter(|v| !v.is_empty())
+    };
+    if let Some(name) = tag("friendlyName") {
+        tv.name = Some(name);
+    }
+    // `modelName` is often just "LG TV"; `modelNumber` has the actual model.
+    if let Some(model) = tag("modelNumber").or_else(|| tag("modelName")) {
+        tv.model = Some(model);
+    }
+    if let Some(udn) = tag("UDN") {
+        tv.uuid = Some(udn.trim_start_matches("uuid:").to_string());
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = text.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Fetches a description over plain HTTP. The URL is whatever the TV put in `LOCATION`,
+/// always `http://host:port/path`.
+async fn fetch(location: &str) -> Result<String, String> {
+    let rest = location.strip_prefix("http://").ok_or_else(|| format!("unsupported URL {}", location))?;
+    let (host, path) = rest.split_once('/').map(|(h, p)| (h, format!("/{}", p))).unwrap_or((rest, "/".to_string()));
+    let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+
+    let mut stream = TcpStream::connect(&addr).await.map_err(|e| format!("{}: {}", addr, e))?;
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+    stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.map_err(|e| e.to_string())?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("truncated HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("{}: {}", location, status));
+    }
+    Ok(body.to_string())
+}
+
+/// Sends an `M-SEARCH` to `target` from a fresh socket, which the answers come back to.
+async fn send_search(target: SocketAddr) -> Result<UdpSocket, LgtvError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| LgtvError::Discovery(format!("SSDP: {}", e)))?;
+    let request = m_search();
+    // UDP may drop a datagram; TVs answer each one, and callers ignore duplicates.
+    for _ in 0..2 {
+        socket
+            .send_to(request.as_bytes(), target)
+            .await
+            .map_err(|e| LgtvError::Discovery(format!("SSDP: cannot send to {}: {}", target, e)))?;
+    }
+    Ok(socket)
+}
+
+/// Asks the device at `target` alone and returns its answer as soon as it comes, without
+/// reading the description. `None` if it doesn't answer within `wait`.
+pub async fn query(target: SocketAddr, wait: Duration) -> Result<Option<TvDescriptor>, LgtvError> {
+    let socket = send_search(target).await?;
+    let deadline = Instant::now() + wait;
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Ok((len, from)) = received else { continue };
+        if from.ip() != target.ip() {
+            continue;
+        }
+        if let Some(tv) = parse_response(&String::from_utf8_lossy(&buf[..len]), from.ip()) {
+            return Ok(Some(tv));
+        }
+    }
+    Ok(None)
+}
+
+/// Sends an `M-SEARCH` to `target` (normally [`MULTICAST`]) and collects the TVs that answer
+/// within `wait`, then reads their descriptions, each bounded by `wait` again. A TV whose
+/// description can't be read is still returned with what its response said.
+pub async fn search(target: SocketAddr, wait: Duration) -> Result<Vec<TvDescriptor>, LgtvError> {
+    let socket = send_search(target).await?;
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<TvDescriptor> = Vec::new();
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Ok((len, from)) = received else { continue };
+        let Some(tv) = parse_response(&String::from_utf8_lossy(&buf[..len]), from.ip()) else { continue };
+        if !found.iter().any(|seen| seen.ip == tv.ip) {
+            found.push(tv);
+        }
+    }
+
+    let described = found.into_iter().map(|mut tv| async move {
+        if let Some(location) = tv.location.clone()
+            && let Ok(Ok(xml)) = timeout(wait, fetch(&location)).await
+        {
+            parse_description(&xml, &mut tv);
+        }
+        tv
+    });
+    Ok(join_all(described).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+CACHE-CONTROL: max-age=1800\r\n\
+DATE: Sat, 01 Jan 2000 00:00:00 GMT\r\n\
+EXT:\r\n\
+LOCATION: http://192.168.1.20:1150/\r\n\
+SERVER: WebOS/4.1.0 UPnP/1.0\r\n\
+ST: urn:lge-com:service:webos-second-screen:1\r\n\
+USN: uuid:4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b::urn:lge-com:service:webos-second-screen:1\r\n\
+DLNADeviceName.lge.com: %5bLG%5d%20webOS%20TV%20OLED55C9PLA\r\n\r\n";
+
+    const DESCRIPTION: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
+    <friendlyName>Living Room &amp; Kitchen</friendlyName>
+    <manufacturer>LG Electronics</manufacturer>
+    <modelName>LG Smart TV</modelName>
+    <modelNumber>OLED55C9PLA</modelNumber>
+    <UDN>uuid:4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b</UDN>
+  </device>
+</root>"#;
+
+    #[test]
+    fn parses_search_response() {
+        let tv = parse_response(RESPONSE, "192.168.1.20".parse().unwrap()).unwrap();
+        assert_eq!(
+            tv,
+            TvDescriptor {
+                ip: "192.168.1.20".into(),
+                name: Some("[LG] webOS TV OLED55C9PLA".into()),
+                model: None,
+                uuid: Some("4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b".into()),
+                location: Some("http://192.168.1.20:1150/".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        let other = RESPONSE.replace(SEARCH_TARGET, "urn:schemas-upnp-org:device:MediaRenderer:1");
+        assert_eq!(parse_response(&other, "192.168.1.20".parse().unwrap()), None);
+        assert_eq!(parse_response("NOTIFY * HTTP/1.1\r\n\r\n", "192.168.1.20".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn parses_description() {
+        let mut tv = TvDescriptor { ip: "192.168.1.20".into(), ..Default::default() };
+        parse_description(DESCRIPTION, &mut tv);
+        assert_eq!(tv.name.as_deref(), Some("Living Room & Kitchen"));
+        assert_eq!(tv.model.as_deref(), Some("OLED55C9PLA"));
+        assert_eq!(tv.uuid.as_deref(), Some("4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b"));
+    }
+
+    /// A TV on localhost: answers M-SEARCH over UDP and serves its description over HTTP.
+    async fn fake_tv() -> SocketAddr {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/description.xml", http.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = http.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\n\r\n{}", DESCRIPTION);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = ssdp.recv_from(&mut buf).await {
+                let request = String::from_utf8_lossy(&buf[..len]);
+                if request.starts_with("M-SEARCH") && request.contains(SEARCH_TARGET) {
+                    let response = RESPONSE.replace("http://192.168.1.20:1150/", &location);
+                    let _ = ssdp.send_to(response.as_bytes(), from).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn finds_fake_tv() {
+        let addr = fake_tv().await;
+        let found = search(addr, Duration::from_millis(300)).await.unwrap();
+        assert_eq!(found.len(), 1, "{:?}", found);
+        let tv = &found[0];
+        assert_eq!(tv.ip, "127.0.0.1");
+        assert_eq!(tv.name.as_deref(), Some("Living Room & Kitchen"));
+        assert_eq!(tv.model.as_deref(), Some("OLED55C9PLA"));
+        assert_eq!(tv.uuid.as_deref(), Some("4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b"));
+    }
+
+    #[tokio::test]
+    async fn query_returns_first_answer() {
+        let addr = fake_tv().await;
+        let started = Instant::now();
+        let tv = query(addr, Duration::from_secs(5)).await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1), "waited {:?}", started.elapsed());
+        assert_eq!(tv.uuid.as_deref(), Some("4d1f0b1c-26a8-4f8e-9a4b-0c6f1e2d3a4b"));
+        // The description isn't fetched.
+        assert_eq!(tv.model, None);
+    }
+
+    #[tokio::test]
+    async fn query_without_answer() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let found = query(silent.local_addr().unwrap(), Duration::from_millis(200)).await.unwrap();
+        assert_eq!(found, None);
+    }
+}
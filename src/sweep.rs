@@ -0,0 +1,181 @@
+//! Priming the neighbour table.
+//!
+//! The kernel only learns a MAC address once something is sent to its IP address. Sending a
+//! tiny UDP datagram to every address on the local subnet makes it resolve them all, after
+//! which the TV's MAC can be looked up. The datagrams go to the TV's SSAP port (3000); other
+//! hosts ignore them or answer with an ICMP error.
+//!
+//! Only interfaces that are up and have a link are swept, the one with the default route
+//! first, so a container bridge or VPN tunnel doesn't use up the time limit before the LAN.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Command;
+use std::time::Duration;
+
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::InterfaceFlags;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, MissedTickBehavior, interval, sleep_until};
+
+use crate::error::LgtvError;
+
+const PORT: u16 = 3000;
+
+/// Networks wider than this prefix are only swept around our own address.
+const MIN_PREFIX: u32 = 24;
+
+/// How long to leave for ARP replies after the last probe.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Parses Linux `/proc/net/route` for the interface of the default route with the lowest
+/// metric: `eth0  00000000  0101A8C0  0003  0  0  100  00000000 ...`, after a header line.
+fn parse_proc_net_route(text: &str) -> Option<String> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [iface, destination, _gateway, flags, _refcnt, _use, metric, mask, ..] = fields.as_slice() else {
+                return None;
+            };
+            // RTF_UP (0x1).
+            let up = u32::from_str_radix(flags, 16).is_ok_and(|f| f & 1 != 0);
+            if *destination != "00000000" || *mask != "00000000" || !up {
+                return None;
+            }
+            Some((metric.parse::<u32>().ok()?, *iface))
+        })
+        .min()
+        .map(|(_, iface)| iface.to_string())
+}
+
+/// Parses BSD/macOS `route -n get default` output for the `interface: en0` line.
+fn parse_route_get(output: &str) -> Option<String> {
+    output.lines().find_map(|line| line.trim().strip_prefix("interface:").map(|i| i.trim().to_string()))
+}
+
+/// The interface the default route goes through, if it can be found out.
+fn default_interface() -> Option<String> {
+    if let Ok(text) = std::fs::read_to_string("/proc/net/route") {
+        return parse_proc_net_route(&text);
+    }
+    let out = Command::new("route").args(["-n", "get", "default"]).output().ok()?;
+    parse_route_get(&String::from_utf8_lossy(&out.stdout))
+}
+
+/// This machine's IPv4 addresses on interfaces that are up and running, with their prefix
+/// lengths, the default route's interface first. Point-to-point links (VPN tunnels) are left
+/// out: there is no LAN behind them to sweep.
+fn local_networks() -> Result<Vec<(Ipv4Addr, u32)>, String> {
+    let default = default_interface();
+    let mut networks = Vec::new();
+    for ifa in getifaddrs().map_err(|e| format!("cannot list network interfaces: {}", e))? {
+        if !ifa.flags.contains(InterfaceFlags::IFF_UP | InterfaceFlags::IFF_RUNNING)
+            || ifa.flags.intersects(InterfaceFlags::IFF_LOOPBACK | InterfaceFlags::IFF_POINTOPOINT)
+        {
+            continue;
+        }
+        let addr = ifa.address.as_ref().and_then(|a| a.as_sockaddr_in()).map(|a| a.ip());
+        let mask = ifa.netmask.as_ref().and_then(|a| a.as_sockaddr_in()).map(|a| a.ip());
+        if let (Some(addr), Some(mask)) = (addr, mask) {
+            let is_default = default.as_deref() == Some(ifa.interface_name.as_str());
+            networks.push((!is_default, addr, u32::from(mask).count_ones()));
+        }
+    }
+    // Stable, so the rest keep the order the system lists them in.
+    networks.sort_by_key(|&(later, ..)| later);
+    Ok(networks.into_iter().map(|(_, addr, prefix)| (addr, prefix)).collect())
+}
+
+/// Every host address on the given networks, except our own. Networks wider than a /24 are
+/// narrowed to the /24 around our address, to keep the sweep short.
+fn targets(networks: &[(Ipv4Addr, u32)]) -> Vec<Ipv4Addr> {
+    let mut hosts = Vec::new();
+    for &(addr, prefix) in networks {
+        let prefix = prefix.max(MIN_PREFIX);
+        if prefix >= 31 {
+            continue;
+        }
+        let mask = u32::MAX << (32 - prefix);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        for host in (network + 1)..broadcast {
+            let host = Ipv4Addr::from(host);
+            if host != addr && !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+    }
+    hosts
+}
+
+/// Probes every address on the local subnets, at most `rate` per second, and stops after
+/// `limit` whether or not it got through them all. Returns how many addresses were probed.
+pub async fn sweep(rate: u32, limit: Duration) -> Result<usize, LgtvError> {
+    let deadline = Instant::now() + limit;
+    let hosts = targets(&local_networks().map_err(LgtvError::Discovery)?);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| LgtvError::Discovery(format!("sweep: {}", e)))?;
+
+    let mut ticks = interval(Duration::from_secs(1) / rate.max(1));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut probed = 0;
+    for host in hosts {
+        if tokio::time::timeout_at(deadline, ticks.tick()).await.is_err() {
+            break;
+        }
+        // Unreachable hosts and full neighbour queues are expected; keep going.
+        let _ = socket.send_to(&[0], SocketAddr::from((host, PORT))).await;
+        probed += 1;
+    }
+    sleep_until((Instant::now() + SETTLE).min(deadline)).await;
+    Ok(probed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_default_route_on_linux() {
+        let text = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+docker0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+tun0\t00000000\t00000000\t0000\t0\t0\t50\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        // tun0's route isn't up; of the others the lowest metric wins.
+        assert_eq!(parse_proc_net_route(text).as_deref(), Some("eth0"));
+        assert_eq!(parse_proc_net_route(text.lines().take(2).collect::<Vec<_>>().join("\n").as_str()), None);
+    }
+
+    #[test]
+    fn finds_default_route_on_macos() {
+        let output = "   route to: default
+destination: default
+       mask: default
+    gateway: 192.168.1.1
+  interface: en0
+      flags: <UP,GATEWAY,DONE,STATIC,PRCLONING,GLOBAL>
+";
+        assert_eq!(parse_route_get(output).as_deref(), Some("en0"));
+        assert_eq!(parse_route_get("route: writing to routing socket: not in table\n"), None);
+    }
+
+    #[test]
+    fn sweeps_networks_in_order() {
+        let lan = Ipv4Addr::new(192, 168, 1, 20);
+        let bridge = Ipv4Addr::new(172, 17, 0, 1);
+        let hosts = targets(&[(lan, 24), (bridge, 16)]);
+        // A /16 is narrowed to the /24 around the address.
+        assert_eq!(hosts.len(), 253 + 253);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[252], Ipv4Addr::new(192, 168, 1, 254));
+        assert_eq!(hosts[253], Ipv4Addr::new(172, 17, 0, 2));
+        assert!(!hosts.contains(&lan) && !hosts.contains(&bridge));
+        // Point-to-point sized networks have no one else on them.
+        assert!(targets(&[(Ipv4Addr::new(10, 8, 0, 2), 31), (Ipv4Addr::new(10, 8, 0, 3), 32)]).is_empty());
+    }
+}
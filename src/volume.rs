@@ -0,0 +1,167 @@
+//! Locally tracked volume and mute state.
+//!
+//! Seeded with `GetVolume` on connect and kept current through the `ssap://audio/getVolume`
+//! subscription, so changes made with the physical remote are picked up too. Volume steps
+//! can then be sent as a single `SetVolume` without reading the volume first.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::VolumeConfig;
+use crate::connection::TvConnection;
+use crate::error::LgtvError;
+
+const VOLUME_URI: &str = "ssap://audio/getVolume";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeState {
+    pub volume: Option<u8>,
+    pub muted: Option<bool>,
+}
+
+impl VolumeState {
+    /// Reads a `getVolume` payload. Newer webOS nests the values in `volumeStatus` and calls
+    /// the mute flag `muteStatus`; older versions use top-level `volume` / `muted`.
+    pub fn from_payload(payload: &Value) -> Self {
+        let status = payload.get("volumeStatus").unwrap_or(payload);
+        VolumeState {
+            volume: status.get("volume").and_then(Value::as_u64).map(|v| v.min(100) as u8),
+            muted: status.get("muteStatus").or_else(|| status.get("muted")).and_then(Value::as_bool),
+        }
+    }
+
+    /// Takes every value `other` knows, keeping ours for the rest.
+    pub fn merge(&mut self, other: VolumeState) {
+        self.volume = other.volume.or(self.volume);
+        self.muted = other.muted.or(self.muted);
+    }
+}
+
+pub type SharedVolume = Arc<Mutex<VolumeState>>;
+
+/// Subscribes to volume changes on `tv` and applies them to its volume model until the
+/// connection closes.
+pub fn spawn_watcher(tv: &TvConnection) -> Result<(), LgtvError> {
+    let mut messages = tv.messages();
+    let id = tv.subscribe(VOLUME_URI)?;
+    let state = tv.volume.clone();
+
+    tokio::spawn(async move {
+        loop {
+            match messages.recv().await {
+                Ok(msg) => {
+                    if msg.get("id").and_then(Value::as_str) != Some(id.as_str()) {
+                        continue;
+                    }
+                    if let Some(payload) = msg.get("payload") {
+                        state.lock().unwrap().merge(VolumeState::from_payload(payload));
+                    }
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Turns volume key presses into signed deltas, applying the configured step and acceleration.
+pub struct VolumeStepper {
+    step: i64,
+    accel_window: Option<Duration>,
+    accel_max_step: i64,
+    /// Direction and time of the previous press, and how many presses the current run has.
+    last: Option<(i64, Instant)>,
+    streak: i64,
+}
+
+impl VolumeStepper {
+    pub fn new(config: &VolumeConfig) -> Self {
+        VolumeStepper {
+            step: config.step as i64,
+            accel_window: (config.accel_window_ms > 0).then(|| Duration::from_millis(config.accel_window_ms)),
+            accel_max_step: config.accel_max_step as i64,
+            last: None,
+            streak: 0,
+        }
+    }
+
+    /// Delta for one press in `direction` (+1 / -1). An explicit step is used as-is and
+    /// doesn't take part in acceleration.
+    pub fn delta(&mut self, direction: i64, explicit: Option<u8>) -> i64 {
+        if let Some(step) = explicit {
+            self.last = None;
+            return direction * step as i64;
+        }
+
+        let now = Instant::now();
+        let repeat = match (self.accel_window, self.last) {
+            (Some(window), Some((dir, at))) => dir == direction && now.duration_since(at) < window,
+            _ => false,
+        };
+        self.streak = if repeat { self.streak + 1 } else { 0 };
+        self.last = Some((direction, now));
+
+        direction * (self.step + self.streak).min(self.accel_max_step.max(self.step))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stepper(step: u8, accel_window_ms: u64, accel_max_step: u8) -> VolumeStepper {
+        VolumeStepper::new(&VolumeConfig { step, accel_window_ms, accel_max_step, ..Default::default() })
+    }
+
+    #[test]
+    fn steps_without_acceleration() {
+        let mut s = stepper(2, 0, 5);
+        assert_eq!([s.delta(1, None), s.delta(1, None), s.delta(-1, None)], [2, 2, -2]);
+    }
+
+    #[test]
+    fn accelerates_up_to_max_step() {
+        let mut s = stepper(1, 60_000, 3);
+        let deltas: Vec<i64> = (0..5).map(|_| s.delta(1, None)).collect();
+        assert_eq!(deltas, [1, 2, 3, 3, 3]);
+    }
+
+    #[test]
+    fn direction_change_and_pause_restart_the_streak() {
+        let mut s = stepper(1, 60_000, 5);
+        assert_eq!([s.delta(1, None), s.delta(1, None), s.delta(-1, None), s.delta(-1, None)], [1, 2, -1, -2]);
+
+        let mut s = stepper(1, 5, 5);
+        assert_eq!([s.delta(1, None), s.delta(1, None)], [1, 2]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(s.delta(1, None), 1);
+    }
+
+    #[test]
+    fn explicit_step_resets_the_streak() {
+        let mut s = stepper(1, 60_000, 5);
+        assert_eq!([s.delta(1, None), s.delta(1, None)], [1, 2]);
+        assert_eq!(s.delta(1, Some(10)), 10);
+        assert_eq!(s.delta(1, None), 1);
+        assert_eq!(s.delta(-1, Some(4)), -4);
+    }
+
+    #[test]
+    fn max_step_below_step_keeps_step() {
+        // Only reachable with acceleration off, where config validation allows it.
+        let mut s = stepper(4, 0, 1);
+        assert_eq!(s.delta(1, None), 4);
+    }
+
+    #[test]
+    fn reads_both_payload_shapes() {
+        let nested = serde_json::json!({"volumeStatus": {"volume": 12, "muteStatus": true}});
+        assert_eq!(VolumeState::from_payload(&nested), VolumeState { volume: Some(12), muted: Some(true) });
+        let flat = serde_json::json!({"volume": 150, "muted": false});
+        assert_eq!(VolumeState::from_payload(&flat), VolumeState { volume: Some(100), muted: Some(false) });
+    }
+}